@@ -0,0 +1,13 @@
+-- Encrypted tokens can't be recovered here, affected accounts need their token set again
+UPDATE kideaccounts
+SET token = ''
+WHERE token IS NULL;
+
+ALTER TABLE kideaccounts
+ALTER COLUMN token SET NOT NULL;
+
+ALTER TABLE kideaccounts
+DROP COLUMN IF EXISTS token_key_id,
+DROP COLUMN IF EXISTS token_wrapped_key,
+DROP COLUMN IF EXISTS token_ciphertext,
+DROP COLUMN IF EXISTS token_expires_at;
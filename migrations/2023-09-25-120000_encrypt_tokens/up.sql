@@ -0,0 +1,11 @@
+-- Tokens are encrypted by the application, see KideAccount::encrypt_plaintext_tokens which moves
+-- existing plaintext tokens into these columns on startup
+ALTER TABLE kideaccounts
+ADD COLUMN token_key_id TEXT,
+ADD COLUMN token_wrapped_key BYTEA,
+ADD COLUMN token_ciphertext BYTEA,
+ADD COLUMN token_expires_at TIMESTAMPTZ;
+
+-- The plaintext column is cleared once a token has been encrypted
+ALTER TABLE kideaccounts
+ALTER COLUMN token DROP NOT NULL;
@@ -0,0 +1,3 @@
+-- Shown in place of the token, which is never returned by the API
+ALTER TABLE kideaccounts
+ADD COLUMN token_preview TEXT;
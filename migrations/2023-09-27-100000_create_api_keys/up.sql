@@ -0,0 +1,12 @@
+-- Only a SHA-256 hash of each key is stored, the key itself is shown once when it's issued
+CREATE TABLE api_keys (
+    uuid UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name TEXT NOT NULL,
+    key_hash BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX api_keys_key_hash_index
+ON api_keys (key_hash);
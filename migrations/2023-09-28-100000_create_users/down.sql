@@ -0,0 +1,11 @@
+UPDATE fang_tasks
+SET metadata = metadata - 'ownerId'
+WHERE metadata->>'type' = 'ScalpingTask';
+
+ALTER TABLE api_keys
+DROP COLUMN IF EXISTS user_uuid;
+
+ALTER TABLE kideaccounts
+DROP COLUMN IF EXISTS owner_uuid;
+
+DROP TABLE users;
@@ -0,0 +1,33 @@
+CREATE TABLE users (
+    uuid UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name TEXT NOT NULL,
+    role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('admin', 'user')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX users_name_index
+ON users (name);
+
+-- Everything that exists so far belongs to an initial admin
+INSERT INTO users (name, role)
+VALUES ('admin', 'admin');
+
+ALTER TABLE kideaccounts
+ADD COLUMN owner_uuid UUID REFERENCES users (uuid) ON DELETE SET NULL;
+
+UPDATE kideaccounts
+SET owner_uuid = (SELECT uuid FROM users WHERE name = 'admin');
+
+ALTER TABLE api_keys
+ADD COLUMN user_uuid UUID REFERENCES users (uuid) ON DELETE CASCADE;
+
+UPDATE api_keys
+SET user_uuid = (SELECT uuid FROM users WHERE name = 'admin');
+
+ALTER TABLE api_keys
+ALTER COLUMN user_uuid SET NOT NULL;
+
+-- Tasks live in fang's table, their owner is kept in the task metadata
+UPDATE fang_tasks
+SET metadata = jsonb_set(metadata, '{ownerId}', to_jsonb((SELECT uuid FROM users WHERE name = 'admin')::text))
+WHERE metadata->>'type' = 'ScalpingTask';
@@ -0,0 +1,12 @@
+-- Progress of tasks as reported by the workers, keyed by the task's event id
+CREATE TABLE task_events (
+    id BIGSERIAL PRIMARY KEY,
+    event_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL,
+    account_uuid UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX task_events_event_id_index
+ON task_events (event_id, id);
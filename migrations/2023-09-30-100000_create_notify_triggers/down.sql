@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS task_events_notify ON task_events;
+DROP FUNCTION IF EXISTS crystal_notify_task_event();
+
+DROP TRIGGER IF EXISTS fang_tasks_notify ON fang_tasks;
+DROP FUNCTION IF EXISTS crystal_notify_task();
@@ -0,0 +1,32 @@
+-- Wakes up workers when a task is scheduled, updated or retried, see notify.rs
+CREATE OR REPLACE FUNCTION crystal_notify_task() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'crystal_tasks',
+        json_build_object('id', NEW.id, 'scheduledAt', NEW.scheduled_at)::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER fang_tasks_notify
+AFTER INSERT OR UPDATE OF metadata, scheduled_at ON fang_tasks
+FOR EACH ROW
+WHEN (NEW.state = 'new' OR NEW.state = 'retried')
+EXECUTE PROCEDURE crystal_notify_task();
+
+-- Lets any GraphQL server relay task events, no matter which worker recorded them
+CREATE OR REPLACE FUNCTION crystal_notify_task_event() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'crystal_events',
+        json_build_object('id', NEW.id, 'eventId', NEW.event_id)::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER task_events_notify
+AFTER INSERT ON task_events
+FOR EACH ROW
+EXECUTE PROCEDURE crystal_notify_task_event();
@@ -0,0 +1,13 @@
+-- What a task decided for each account, with the ranking the decision was based on
+CREATE TABLE task_results (
+    id BIGSERIAL PRIMARY KEY,
+    event_id TEXT NOT NULL,
+    account_uuid UUID,
+    variant_id TEXT,
+    variant_name TEXT,
+    ranking JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX task_results_event_id_index
+ON task_results (event_id, id);
@@ -0,0 +1,4 @@
+ALTER TABLE kideaccounts
+DROP COLUMN has_student_card,
+DROP COLUMN has_haka,
+DROP COLUMN membership_ids;
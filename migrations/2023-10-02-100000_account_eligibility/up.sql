@@ -0,0 +1,11 @@
+-- What each account is able to buy, variants with requirements an account doesn't meet are
+-- never reserved for it
+ALTER TABLE kideaccounts
+ADD COLUMN has_student_card BOOLEAN NOT NULL DEFAULT FALSE,
+ADD COLUMN has_haka BOOLEAN NOT NULL DEFAULT FALSE,
+ADD COLUMN membership_ids TEXT[] NOT NULL DEFAULT '{}';
+
+-- Student card and HAKA requirements weren't checked before, so existing accounts keep being
+-- allowed to buy those variants until their profile says otherwise. Membership variants were
+-- already skipped by default, so existing accounts start without memberships like new ones.
+UPDATE kideaccounts SET has_student_card = TRUE, has_haka = TRUE;
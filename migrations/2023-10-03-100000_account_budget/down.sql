@@ -0,0 +1,2 @@
+ALTER TABLE kideaccounts
+DROP COLUMN budget;
@@ -0,0 +1,3 @@
+-- Most an account may spend in a single task, in cents. NULL means no limit.
+ALTER TABLE kideaccounts
+ADD COLUMN budget INTEGER;
@@ -0,0 +1,3 @@
+ALTER TABLE task_results
+DROP COLUMN requested,
+DROP COLUMN granted;
@@ -0,0 +1,5 @@
+-- Quantity asked for and quantity found in the basket afterwards. granted stays NULL until the
+-- basket could be read.
+ALTER TABLE task_results
+ADD COLUMN requested INTEGER,
+ADD COLUMN granted INTEGER;
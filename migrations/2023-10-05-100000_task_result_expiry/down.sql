@@ -0,0 +1,3 @@
+ALTER TABLE task_results
+DROP COLUMN expires_at,
+DROP COLUMN remind_at;
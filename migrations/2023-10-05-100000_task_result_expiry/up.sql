@@ -0,0 +1,5 @@
+-- When the hold on the reserved tickets lapses unless checked out, NULL if the basket didn't say.
+-- remind_at is set once a reminder has been scheduled for the hold.
+ALTER TABLE task_results
+ADD COLUMN expires_at TIMESTAMPTZ,
+ADD COLUMN remind_at TIMESTAMPTZ;
@@ -0,0 +1,28 @@
+-- The secret signs the payloads, so it's encrypted like the account tokens instead of hashed
+CREATE TABLE webhooks (
+    uuid UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    owner_uuid UUID REFERENCES users (uuid) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret_key_id TEXT NOT NULL,
+    secret_wrapped_key BYTEA NOT NULL,
+    secret_ciphertext BYTEA NOT NULL,
+    -- Task event kinds to send, an empty filter sends the task lifecycle
+    events TEXT[] NOT NULL DEFAULT '{}',
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- One row per delivery attempt, retries included
+CREATE TABLE webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_uuid UUID NOT NULL REFERENCES webhooks (uuid) ON DELETE CASCADE,
+    task_event_id BIGINT NOT NULL,
+    event_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    status_code INTEGER,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX webhook_deliveries_webhook_uuid_index
+ON webhook_deliveries (webhook_uuid);
@@ -0,0 +1,3 @@
+-- How payloads are shaped for the receiver, see WebhookFormat
+ALTER TABLE webhooks
+ADD COLUMN format TEXT NOT NULL DEFAULT 'raw';
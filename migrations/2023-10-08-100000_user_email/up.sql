@@ -0,0 +1,3 @@
+-- Where task outcomes and token expiry warnings are mailed, nothing is mailed without one
+ALTER TABLE users
+ADD COLUMN email TEXT;
@@ -0,0 +1,17 @@
+CREATE OR REPLACE FUNCTION crystal_notify_task_event() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'crystal_events',
+        json_build_object('id', NEW.id, 'eventId', NEW.event_id)::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP INDEX task_events_event_id_index;
+
+CREATE INDEX task_events_event_id_index
+ON task_events (event_id, id);
+
+ALTER TABLE task_events
+DROP COLUMN owner_uuid;
@@ -0,0 +1,25 @@
+-- Every owner can have a task for the same event, so events are kept per event and owner. Events
+-- recorded before are given the owner of the task for their event, there was only ever one.
+ALTER TABLE task_events
+ADD COLUMN owner_uuid UUID;
+
+UPDATE task_events SET owner_uuid = (fang_tasks.metadata->>'ownerId')::uuid
+FROM fang_tasks
+WHERE fang_tasks.metadata->>'type' = 'ScalpingTask'
+AND fang_tasks.metadata->>'eventId' = task_events.event_id;
+
+DROP INDEX task_events_event_id_index;
+
+CREATE INDEX task_events_event_id_index
+ON task_events (event_id, owner_uuid, id);
+
+-- Subscribers are only woken up by the events of their own task
+CREATE OR REPLACE FUNCTION crystal_notify_task_event() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'crystal_events',
+        json_build_object('id', NEW.id, 'eventId', NEW.event_id, 'ownerId', NEW.owner_uuid)::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
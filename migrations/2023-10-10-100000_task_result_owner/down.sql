@@ -0,0 +1,7 @@
+DROP INDEX task_results_event_id_index;
+
+CREATE INDEX task_results_event_id_index
+ON task_results (event_id, id);
+
+ALTER TABLE task_results
+DROP COLUMN owner_uuid;
@@ -0,0 +1,14 @@
+-- Results are kept per event and owner like task events. Results recorded before are given the
+-- owner of the task for their event, there was only ever one.
+ALTER TABLE task_results
+ADD COLUMN owner_uuid UUID;
+
+UPDATE task_results SET owner_uuid = (fang_tasks.metadata->>'ownerId')::uuid
+FROM fang_tasks
+WHERE fang_tasks.metadata->>'type' = 'ScalpingTask'
+AND fang_tasks.metadata->>'eventId' = task_results.event_id;
+
+DROP INDEX task_results_event_id_index;
+
+CREATE INDEX task_results_event_id_index
+ON task_results (event_id, owner_uuid, id);
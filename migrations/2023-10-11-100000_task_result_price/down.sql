@@ -0,0 +1,2 @@
+ALTER TABLE task_results
+DROP COLUMN price_per_item;
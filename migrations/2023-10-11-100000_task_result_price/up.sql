@@ -0,0 +1,4 @@
+-- Price of the chosen variant when the result was recorded, in cents. Budgets are checked against
+-- what an account already spent in the task. Older results have no price and count as free.
+ALTER TABLE task_results
+ADD COLUMN price_per_item BIGINT;
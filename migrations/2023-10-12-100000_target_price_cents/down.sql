@@ -0,0 +1,8 @@
+UPDATE fang_tasks
+SET metadata = jsonb_set(
+    metadata,
+    '{options,targetPrice}',
+    to_jsonb((metadata->'options'->>'targetPrice')::INTEGER / 100)
+)
+WHERE metadata->>'type' = 'ScalpingTask'
+AND jsonb_typeof(metadata->'options'->'targetPrice') = 'number';
@@ -0,0 +1,9 @@
+-- Target prices of scheduled tasks were in whole euros, they are in cents like the price range
+UPDATE fang_tasks
+SET metadata = jsonb_set(
+    metadata,
+    '{options,targetPrice}',
+    to_jsonb((metadata->'options'->>'targetPrice')::INTEGER * 100)
+)
+WHERE metadata->>'type' = 'ScalpingTask'
+AND jsonb_typeof(metadata->'options'->'targetPrice') = 'number';
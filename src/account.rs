@@ -1,4 +1,11 @@
+use crate::api::{format_cents, Variant};
+use crate::crypto::{get_keyring, CryptoError, SealedToken};
 use crate::db::{get_db_manager, DBError};
+use crate::secret::Secret;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use fang::{FangError, ToFangError};
 use juniper::GraphQLObject;
 use serde::{Deserialize, Serialize};
 use tokio_postgres::Row;
@@ -6,43 +13,224 @@ use uuid::Uuid;
 
 pub type AccountIDList = Vec<Uuid>;
 
+#[derive(thiserror::Error, Debug, ToFangError)]
+pub enum AccountError {
+    #[error("Database error")]
+    DBError(#[from] DBError),
+
+    #[error("Token encryption error")]
+    CryptoError(#[from] CryptoError),
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+#[graphql(description = "What an account is able to buy")]
+pub struct Eligibility {
+    pub has_student_card: bool,
+    pub has_haka: bool,
+    // Ids of the Kide memberships the account holds
+    pub membership_ids: Vec<String>,
+    #[graphql(
+        description = "Most the account may spend on everything it reserves in a task, in cents"
+    )]
+    pub budget: Option<i32>,
+}
+
+impl Eligibility {
+    // Why the account can't buy the variant, None if it can
+    pub fn ineligibility_reason(&self, variant: &Variant) -> Option<String> {
+        if !self.within_budget(variant.price_per_item) {
+            return Some(format!(
+                "Costs {}, over the budget of {}",
+                format_cents(variant.price_per_item),
+                format_cents(self.budget.unwrap_or_default() as i64)
+            ));
+        }
+
+        if variant.is_product_variant_haka_authentication_required && !self.has_haka {
+            return Some("Requires HAKA authentication".to_string());
+        }
+
+        if variant.is_product_variant_student_card_required && !self.has_student_card {
+            return Some("Requires a student card".to_string());
+        }
+
+        if variant.is_product_variant_membership_required {
+            let memberships = variant
+                .access_control_memberships
+                .as_deref()
+                .unwrap_or_default();
+
+            // Without the list of accepted memberships any membership has to do
+            let eligible = if memberships.is_empty() {
+                !self.membership_ids.is_empty()
+            } else {
+                memberships
+                    .iter()
+                    .any(|membership| self.membership_ids.contains(&membership.id))
+            };
+
+            if !eligible {
+                let names: Vec<&str> = memberships
+                    .iter()
+                    .map(|membership| membership.name.as_str())
+                    .collect();
+
+                return Some(if names.is_empty() {
+                    "Requires a membership".to_string()
+                } else {
+                    format!("Requires a membership: {}", names.join(", "))
+                });
+            }
+        }
+
+        None
+    }
+
+    // What is left of the budget after spending `spent` in the task, None without a budget
+    pub fn remaining_budget(&self, spent: i64) -> Option<i64> {
+        self.budget.map(|budget| (budget as i64 - spent).max(0))
+    }
+
+    pub fn within_budget(&self, total: i64) -> bool {
+        match self.budget {
+            Some(budget) => total <= budget as i64,
+            None => true,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, GraphQLObject)]
 #[serde(rename_all = "camelCase")]
 #[graphql(description = "A Kide account")]
 pub struct KideAccount {
     pub uuid: Uuid,
     pub name: String,
-    pub token: String,
+    pub owner_uuid: Option<Uuid>,
+    // Only decrypted right before reserving, see `decrypt_token`. None for tokens stored before
+    // encryption at rest that no worker has encrypted yet, see `encrypt_plaintext_tokens`.
+    #[graphql(ignore)]
+    pub token: Option<SealedToken>,
+    #[graphql(description = "The last characters of the token, for telling tokens apart")]
+    pub token_preview: Option<String>,
+    #[graphql(description = "When the token expires, if it could be read from the token")]
+    pub token_expires_at: Option<DateTime<Utc>>,
+    pub eligibility: Eligibility,
 }
 
 impl<'a> TryFrom<&'a Row> for KideAccount {
     type Error = DBError;
 
     fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        let key_id: Option<String> = row.try_get("token_key_id")?;
+        let wrapped_key: Option<Vec<u8>> = row.try_get("token_wrapped_key")?;
+        let ciphertext: Option<Vec<u8>> = row.try_get("token_ciphertext")?;
+        let token = match (key_id, wrapped_key, ciphertext) {
+            (Some(key_id), Some(wrapped_key), Some(ciphertext)) => Some(SealedToken {
+                key_id,
+                wrapped_key,
+                ciphertext,
+            }),
+            _ => None,
+        };
+
         Ok(Self {
             uuid: row.try_get("uuid")?,
             name: row.try_get("name")?,
-            token: row.try_get("token")?,
+            owner_uuid: row.try_get("owner_uuid")?,
+            token,
+            token_preview: row.try_get("token_preview")?,
+            token_expires_at: row.try_get("token_expires_at")?,
+            eligibility: Eligibility {
+                has_student_card: row.try_get("has_student_card")?,
+                has_haka: row.try_get("has_haka")?,
+                membership_ids: row.try_get("membership_ids")?,
+                budget: row.try_get("budget")?,
+            },
         })
     }
 }
 
+// Reads the `exp` claim from the token's JWT payload. The signature is not verified, this is
+// only used to warn about stale tokens before a sale.
+fn jwt_expiry(token: &Secret) -> Option<DateTime<Utc>> {
+    let payload = token.expose().split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    let exp = claims.get("exp")?.as_i64()?;
+
+    Utc.timestamp_opt(exp, 0).single()
+}
+
 impl KideAccount {
-    pub fn new(uuid: Uuid, name: String, token: String) -> Self {
-        Self { uuid, name, token }
+    pub fn new(uuid: Uuid, name: String, token: &Secret) -> Result<Self, CryptoError> {
+        let mut account = Self {
+            uuid,
+            name,
+            ..Default::default()
+        };
+        account.set_token(token)?;
+
+        Ok(account)
+    }
+
+    pub fn set_token(&mut self, token: &Secret) -> Result<(), CryptoError> {
+        self.token = Some(SealedToken::seal(get_keyring(), token.expose())?);
+        self.token_preview = Some(token.preview());
+        self.token_expires_at = jwt_expiry(token);
+
+        Ok(())
+    }
+
+    pub fn decrypt_token(&self) -> Result<Secret, CryptoError> {
+        let token = self.token.as_ref().ok_or(CryptoError::NotEncrypted)?;
+        Ok(Secret::new(token.open(get_keyring())?))
     }
 
-    pub async fn create(name: String, token: String) -> Result<Self, DBError> {
+    pub async fn create(
+        name: String,
+        token: Secret,
+        owner_uuid: Option<Uuid>,
+        eligibility: Eligibility,
+    ) -> Result<Self, AccountError> {
+        let mut account = Self::new(Uuid::nil(), name, &token)?;
+        account.owner_uuid = owner_uuid;
+        account.eligibility = eligibility;
+
         let db_manager = get_db_manager();
         let conn = db_manager.connection().await?;
 
         let statement = conn
-            .prepare("INSERT INTO kideaccounts (name, token) VALUES ($1, $2) RETURNING uuid")
-            .await?;
-        let row = conn.query_one(&statement, &[&name, &token]).await?;
+            .prepare(
+                "INSERT INTO kideaccounts (name, owner_uuid, token_key_id, token_wrapped_key, \
+                 token_ciphertext, token_preview, token_expires_at, has_student_card, has_haka, \
+                 membership_ids, budget) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+                 RETURNING uuid",
+            )
+            .await
+            .map_err(DBError::from)?;
+        let row = conn
+            .query_one(
+                &statement,
+                &[
+                    &account.name,
+                    &account.owner_uuid,
+                    &account.token.as_ref().map(|token| &token.key_id),
+                    &account.token.as_ref().map(|token| &token.wrapped_key),
+                    &account.token.as_ref().map(|token| &token.ciphertext),
+                    &account.token_preview,
+                    &account.token_expires_at,
+                    &account.eligibility.has_student_card,
+                    &account.eligibility.has_haka,
+                    &account.eligibility.membership_ids,
+                    &account.eligibility.budget,
+                ],
+            )
+            .await
+            .map_err(DBError::from)?;
 
-        let uuid: Uuid = row.get(0);
-        Ok(Self { uuid, name, token })
+        account.uuid = row.get(0);
+        Ok(account)
     }
 
     pub async fn delete(uuid: Uuid) -> Result<(), DBError> {
@@ -60,8 +248,23 @@ impl KideAccount {
 
         db_manager
             .execute(
-                "UPDATE kideaccounts SET name = $1, token = $2 WHERE uuid = $3",
-                &[&self.name, &self.token, &self.uuid],
+                "UPDATE kideaccounts SET name = $1, token_key_id = $2, token_wrapped_key = $3, \
+                 token_ciphertext = $4, token_preview = $5, token_expires_at = $6, \
+                 has_student_card = $7, has_haka = $8, membership_ids = $9, budget = $10 \
+                 WHERE uuid = $11",
+                &[
+                    &self.name,
+                    &self.token.as_ref().map(|token| &token.key_id),
+                    &self.token.as_ref().map(|token| &token.wrapped_key),
+                    &self.token.as_ref().map(|token| &token.ciphertext),
+                    &self.token_preview,
+                    &self.token_expires_at,
+                    &self.eligibility.has_student_card,
+                    &self.eligibility.has_haka,
+                    &self.eligibility.membership_ids,
+                    &self.eligibility.budget,
+                    &self.uuid,
+                ],
             )
             .await?;
 
@@ -94,10 +297,14 @@ impl KideAccount {
         Ok(accounts)
     }
 
-    pub async fn all() -> Result<Vec<KideAccount>, DBError> {
+    // All accounts, or only the ones owned by `owner` if given
+    pub async fn all(owner: Option<Uuid>) -> Result<Vec<KideAccount>, DBError> {
         let db_manager = get_db_manager();
         let rows = db_manager
-            .query("SELECT uuid, name, token FROM kideaccounts", &[])
+            .query(
+                "SELECT * FROM kideaccounts WHERE $1::uuid IS NULL OR owner_uuid = $1",
+                &[&owner],
+            )
             .await?;
 
         let mut accounts = Vec::new();
@@ -108,4 +315,69 @@ impl KideAccount {
 
         Ok(accounts)
     }
+
+    // Encrypts tokens that were stored before encryption at rest was introduced and clears the
+    // plaintext column. Safe to run on every startup.
+    pub async fn encrypt_plaintext_tokens() -> Result<u64, AccountError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT uuid, token FROM kideaccounts WHERE token IS NOT NULL",
+                &[],
+            )
+            .await?;
+
+        let mut encrypted = 0;
+        for row in rows {
+            let uuid: Uuid = row.try_get("uuid").map_err(DBError::from)?;
+            let token: String = row.try_get("token").map_err(DBError::from)?;
+            let token = Secret::new(token);
+
+            let sealed = SealedToken::seal(get_keyring(), token.expose())?;
+            db_manager
+                .execute(
+                    "UPDATE kideaccounts SET token = NULL, token_key_id = $1, token_wrapped_key = $2, \
+                     token_ciphertext = $3, token_preview = $4, token_expires_at = $5 WHERE uuid = $6",
+                    &[
+                        &sealed.key_id,
+                        &sealed.wrapped_key,
+                        &sealed.ciphertext,
+                        &token.preview(),
+                        &jwt_expiry(&token),
+                        &uuid,
+                    ],
+                )
+                .await?;
+
+            encrypted += 1;
+        }
+
+        Ok(encrypted)
+    }
+
+    // Rewraps every token that isn't using the current key of the keyring
+    pub async fn rotate_token_keys() -> Result<u64, AccountError> {
+        let keyring = get_keyring();
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM kideaccounts WHERE token_key_id <> $1",
+                &[&keyring.current_id()],
+            )
+            .await?;
+
+        let mut rotated = 0;
+        for row in rows {
+            let mut account = KideAccount::try_from(&row)?;
+            account.token = match &account.token {
+                Some(token) => Some(token.rewrap(keyring)?),
+                None => continue,
+            };
+            account.save().await?;
+
+            rotated += 1;
+        }
+
+        Ok(rotated)
+    }
 }
@@ -58,6 +58,11 @@ pub struct Price {
     pub eur: i64,
 }
 
+// Prices are in cents everywhere, this is only for messages
+pub fn format_cents(cents: i64) -> String {
+    format!("{}.{:02} €", cents / 100, (cents % 100).abs())
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Variant {
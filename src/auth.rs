@@ -0,0 +1,123 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use openssl::error::ErrorStack;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::db::{get_db_manager, DBError};
+use crate::secret::Secret;
+use crate::user::User;
+
+const KEY_PREFIX: &str = "crystal_";
+const KEY_BYTES: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Database error")]
+    DBError(#[from] DBError),
+
+    #[error("Could not generate key")]
+    OpenSSL(#[from] ErrorStack),
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl<'a> TryFrom<&'a Row> for ApiKey {
+    type Error = DBError;
+
+    fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        Ok(Self {
+            uuid: row.try_get("uuid")?,
+            user_uuid: row.try_get("user_uuid")?,
+            name: row.try_get("name")?,
+            created_at: row.try_get("created_at")?,
+            last_used_at: row.try_get("last_used_at")?,
+            revoked_at: row.try_get("revoked_at")?,
+        })
+    }
+}
+
+fn hash_key(key: &str) -> Vec<u8> {
+    sha256(key.as_bytes()).to_vec()
+}
+
+impl ApiKey {
+    // Creates a new key. The returned secret is the only time the key is available in plaintext.
+    pub async fn issue(user_uuid: Uuid, name: String) -> Result<(Self, Secret), AuthError> {
+        let mut bytes = [0u8; KEY_BYTES];
+        rand_bytes(&mut bytes)?;
+        let key = format!("{}{}", KEY_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_one(
+                "INSERT INTO api_keys (user_uuid, name, key_hash) VALUES ($1, $2, $3) RETURNING *",
+                &[&user_uuid, &name, &hash_key(&key)],
+            )
+            .await?;
+
+        Ok((Self::try_from(&row)?, Secret::new(key)))
+    }
+
+    pub async fn revoke(uuid: Uuid) -> Result<bool, DBError> {
+        let db_manager = get_db_manager();
+        let revoked = db_manager
+            .execute(
+                "UPDATE api_keys SET revoked_at = NOW() WHERE uuid = $1 AND revoked_at IS NULL",
+                &[&uuid],
+            )
+            .await?;
+
+        Ok(revoked > 0)
+    }
+
+    // Looks up an unrevoked key matching the presented one
+    pub async fn authenticate(key: &str) -> Result<Option<Self>, DBError> {
+        if !key.starts_with(KEY_PREFIX) {
+            return Ok(None);
+        }
+
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_opt(
+                "UPDATE api_keys SET last_used_at = NOW() WHERE key_hash = $1 AND revoked_at IS NULL \
+                 RETURNING *",
+                &[&hash_key(key)],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(Self::try_from(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn user(&self) -> Result<Option<User>, DBError> {
+        User::from_uuid(self.user_uuid).await
+    }
+
+    pub async fn all() -> Result<Vec<Self>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query("SELECT * FROM api_keys ORDER BY created_at", &[])
+            .await?;
+
+        let mut keys = Vec::new();
+        for row in rows {
+            keys.push(Self::try_from(&row)?);
+        }
+
+        Ok(keys)
+    }
+}
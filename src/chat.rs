@@ -0,0 +1,607 @@
+use chrono::Duration;
+use juniper::GraphQLEnum;
+use serde_json::{json, Value};
+
+use crate::account::KideAccount;
+use crate::api::format_cents;
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::request::get_client;
+use crate::result::TaskResult;
+use crate::sale::Sale;
+use crate::task::TaskKey;
+use crate::webhook::WebhookPayload;
+
+// Discord embed colors
+const COLOR_SUCCESS: u32 = 0x2ecc71;
+const COLOR_FAILURE: u32 = 0xe74c3c;
+const COLOR_NEUTRAL: u32 = 0x95a5a6;
+
+#[derive(Debug, Clone, Copy, PartialEq, GraphQLEnum)]
+#[graphql(description = "What a webhook's payloads look like")]
+pub enum WebhookFormat {
+    // The event as is, signed
+    Raw,
+    Discord,
+    Slack,
+    // Matrix hookshot style, a plain text and an HTML body
+    Matrix,
+}
+
+impl WebhookFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookFormat::Raw => "raw",
+            WebhookFormat::Discord => "discord",
+            WebhookFormat::Slack => "slack",
+            WebhookFormat::Matrix => "matrix",
+        }
+    }
+
+    pub fn from_db(format: &str) -> Option<Self> {
+        match format {
+            "raw" => Some(WebhookFormat::Raw),
+            "discord" => Some(WebhookFormat::Discord),
+            "slack" => Some(WebhookFormat::Slack),
+            "matrix" => Some(WebhookFormat::Matrix),
+            _ => None,
+        }
+    }
+}
+
+// Events that end a task, these are sent with a summary of everything the task got
+pub fn is_outcome(kind: &str) -> bool {
+    [
+        TaskEventKind::Finished,
+        TaskEventKind::Failed,
+        TaskEventKind::GroupCompleted,
+        TaskEventKind::GroupReleased,
+    ]
+    .iter()
+    .any(|outcome| outcome.as_str() == kind)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReservationSummary {
+    pub account: String,
+    pub variant: String,
+    pub quantity: i64,
+    // Missing when the variant is no longer listed
+    pub price_per_item: Option<i64>,
+}
+
+// What a task ended up with, everything needed to describe it in a message
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskSummary {
+    pub event_id: String,
+    pub event_name: String,
+    pub reservations: Vec<ReservationSummary>,
+    // Accounts that tried but got nothing
+    pub empty_handed: Vec<String>,
+    // From the sale opening to the first reservation
+    pub latency: Option<Duration>,
+}
+
+impl TaskSummary {
+    // Builds the summary from what the task recorded. Kept free of any IO, `load` fetches the
+    // pieces.
+    pub fn build(
+        event_id: &str,
+        sale: Option<&Sale>,
+        results: &[TaskResult],
+        accounts: &[KideAccount],
+        events: &[TaskEvent],
+    ) -> Self {
+        let account_name = |uuid| {
+            accounts
+                .iter()
+                .find(|account| Some(account.uuid) == uuid)
+                .map(|account| account.name.clone())
+                .unwrap_or_else(|| "Unknown account".to_string())
+        };
+
+        // Results that couldn't be checked against the basket are assumed to have gone through
+        let reserved: Vec<&TaskResult> = results
+            .iter()
+            .filter(|result| result.variant_id.is_some())
+            .filter(|result| result.granted.or(result.requested).unwrap_or_default() > 0)
+            .collect();
+
+        let reservations = reserved
+            .iter()
+            .map(|result| ReservationSummary {
+                account: account_name(result.account),
+                variant: result.variant_name.clone().unwrap_or_default(),
+                quantity: result.granted.or(result.requested).unwrap_or_default() as i64,
+                price_per_item: sale.and_then(|sale| {
+                    sale.variants
+                        .iter()
+                        .find(|variant| Some(&variant.id) == result.variant_id.as_ref())
+                        .map(|variant| variant.price_per_item)
+                }),
+            })
+            .collect();
+
+        let mut empty_handed: Vec<String> = Vec::new();
+        for result in results {
+            let name = account_name(result.account);
+            if result.account.is_some()
+                && !reserved.iter().any(|held| held.account == result.account)
+                && !empty_handed.contains(&name)
+            {
+                empty_handed.push(name);
+            }
+        }
+
+        // A task that is run again keeps the events of its earlier runs, the latency is measured
+        // from when the sale opened for the latest one
+        let run = events
+            .iter()
+            .rposition(|event| event.kind == TaskEventKind::Started)
+            .unwrap_or(0);
+        let opened_at = events[run..]
+            .iter()
+            .find(|event| event.kind == TaskEventKind::SaleOpened)
+            .map(|event| event.created_at);
+        let latency = opened_at.and_then(|opened_at| {
+            reserved
+                .iter()
+                .map(|result| result.created_at)
+                .filter(|reserved_at| *reserved_at >= opened_at)
+                .min()
+                .map(|reserved_at| reserved_at - opened_at)
+        });
+
+        Self {
+            event_id: event_id.to_string(),
+            event_name: sale
+                .map(|sale| sale.product.name.clone())
+                .unwrap_or_else(|| event_id.to_string()),
+            reservations,
+            empty_handed,
+            latency,
+        }
+    }
+
+    pub async fn load(task: &TaskKey) -> Self {
+        let event_id = task.event_id.as_str();
+
+        // Only for the name and prices, the summary is still worth sending without them
+        let sale = match get_client().product(event_id.to_string()).await {
+            Ok(sale_client) => Some(sale_client.sale),
+            Err(e) => {
+                log::warn!("Failed to fetch event {} for a summary: {}", event_id, e);
+                None
+            }
+        };
+
+        let results = TaskResult::for_task(task).await.unwrap_or_else(|e| {
+            log::warn!("Failed to fetch results of {}: {}", event_id, e);
+            Vec::new()
+        });
+        let events = TaskEvent::since(task, 0).await.unwrap_or_else(|e| {
+            log::warn!("Failed to fetch events of {}: {}", event_id, e);
+            Vec::new()
+        });
+
+        let mut account_ids: Vec<_> = results.iter().filter_map(|result| result.account).collect();
+        account_ids.sort();
+        account_ids.dedup();
+        let accounts = KideAccount::from_uuids(account_ids)
+            .await
+            .unwrap_or_default();
+
+        Self::build(event_id, sale.as_ref(), &results, &accounts, &events)
+    }
+
+    pub fn tickets(&self) -> i64 {
+        self.reservations
+            .iter()
+            .map(|reservation| reservation.quantity)
+            .sum()
+    }
+
+    pub fn total_cents(&self) -> i64 {
+        self.reservations
+            .iter()
+            .filter_map(|reservation| {
+                reservation
+                    .price_per_item
+                    .map(|price| price * reservation.quantity)
+            })
+            .sum()
+    }
+
+    pub fn headline(&self) -> String {
+        match self.tickets() {
+            0 => format!("Nothing reserved for {}", self.event_name),
+            tickets => format!(
+                "Reserved {} tickets for {} ({})",
+                tickets,
+                self.event_name,
+                format_cents(self.total_cents())
+            ),
+        }
+    }
+
+    // One line per reservation, then the accounts that got nothing and the latency
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .reservations
+            .iter()
+            .map(|reservation| {
+                let price = reservation
+                    .price_per_item
+                    .map(|price| format!(" à {}", format_cents(price)))
+                    .unwrap_or_default();
+
+                format!(
+                    "{}: {} × {}{}",
+                    reservation.account, reservation.quantity, reservation.variant, price
+                )
+            })
+            .collect();
+
+        if !self.empty_handed.is_empty() {
+            lines.push(format!("Nothing for: {}", self.empty_handed.join(", ")));
+        }
+
+        if let Some(latency) = self.latency {
+            lines.push(format!(
+                "First reservation {} ms after the sale opened",
+                latency.num_milliseconds()
+            ));
+        }
+
+        lines
+    }
+}
+
+// The body to post for a payload in the given format. The summary is only used for outcome
+// events.
+pub fn render(
+    format: WebhookFormat,
+    payload: &WebhookPayload,
+    summary: Option<&TaskSummary>,
+) -> Value {
+    match format {
+        WebhookFormat::Raw => json!(payload),
+        WebhookFormat::Discord => render_discord(payload, summary),
+        WebhookFormat::Slack => render_slack(payload, summary),
+        WebhookFormat::Matrix => render_matrix(payload, summary),
+    }
+}
+
+fn title(payload: &WebhookPayload, summary: Option<&TaskSummary>) -> String {
+    match summary {
+        Some(summary) => summary.headline(),
+        None => format!("{}: {}", payload.event_id, payload.message),
+    }
+}
+
+fn color(payload: &WebhookPayload, summary: Option<&TaskSummary>) -> u32 {
+    match summary {
+        Some(summary) if summary.tickets() > 0 => COLOR_SUCCESS,
+        Some(_) => COLOR_FAILURE,
+        None if payload.kind == TaskEventKind::ReservationSucceeded.as_str() => COLOR_SUCCESS,
+        None if payload.kind == TaskEventKind::ReservationFailed.as_str() => COLOR_FAILURE,
+        None => COLOR_NEUTRAL,
+    }
+}
+
+fn render_discord(payload: &WebhookPayload, summary: Option<&TaskSummary>) -> Value {
+    let description = match summary {
+        Some(summary) => summary.lines().join("\n"),
+        None => payload.message.clone(),
+    };
+
+    json!({
+        "content": title(payload, summary),
+        "embeds": [{
+            "title": payload.kind,
+            "description": description,
+            "color": color(payload, summary),
+            "timestamp": payload.created_at.to_rfc3339(),
+            "footer": { "text": payload.event_id },
+        }],
+    })
+}
+
+fn render_slack(payload: &WebhookPayload, summary: Option<&TaskSummary>) -> Value {
+    let title = title(payload, summary);
+    let body = match summary {
+        Some(summary) => summary.lines().join("\n"),
+        None => payload.message.clone(),
+    };
+
+    json!({
+        "text": title,
+        "blocks": [
+            {
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": format!("*{}*", title) },
+            },
+            {
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": body },
+            },
+            {
+                "type": "context",
+                "elements": [{
+                    "type": "mrkdwn",
+                    "text": format!("{} · {}", payload.kind, payload.event_id),
+                }],
+            },
+        ],
+    })
+}
+
+fn render_matrix(payload: &WebhookPayload, summary: Option<&TaskSummary>) -> Value {
+    let title = title(payload, summary);
+    let lines = match summary {
+        Some(summary) => summary.lines(),
+        None => Vec::new(),
+    };
+
+    let mut text = title.clone();
+    let mut html = format!("<strong>{}</strong>", escape_html(&title));
+    if !lines.is_empty() {
+        text.push('\n');
+        text.push_str(&lines.join("\n"));
+
+        html.push_str("<ul>");
+        for line in &lines {
+            html.push_str(&format!("<li>{}</li>", escape_html(line)));
+        }
+        html.push_str("</ul>");
+    }
+
+    json!({
+        "text": text,
+        "html": html,
+    })
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Product, Variant};
+    use chrono::{DateTime, TimeZone, Utc};
+    use uuid::Uuid;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn account(id: u128, name: &str) -> KideAccount {
+        KideAccount {
+            uuid: Uuid::from_u128(id),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn sale() -> Sale {
+        Sale {
+            product: Product {
+                name: "Sitsit <3 & more".to_string(),
+                ..Default::default()
+            },
+            variants: vec![Variant {
+                id: "cabin".to_string(),
+                name: "A-hytti".to_string(),
+                price_per_item: 1250,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn result(
+        account: u128,
+        variant: Option<&str>,
+        granted: Option<i32>,
+        seconds: i64,
+    ) -> TaskResult {
+        TaskResult {
+            id: 0,
+            event_id: "event".to_string(),
+            owner_id: None,
+            account: Some(Uuid::from_u128(account)),
+            variant_id: variant.map(|_| "cabin".to_string()),
+            variant_name: variant.map(|name| name.to_string()),
+            ranking: Vec::new(),
+            requested: variant.map(|_| 2),
+            granted,
+            expires_at: None,
+            created_at: at(seconds),
+        }
+    }
+
+    fn event(kind: TaskEventKind, seconds: i64) -> TaskEvent {
+        TaskEvent {
+            id: seconds,
+            event_id: "event".to_string(),
+            owner_id: None,
+            kind,
+            message: String::new(),
+            account: None,
+            created_at: at(seconds),
+        }
+    }
+
+    fn payload(kind: TaskEventKind) -> WebhookPayload {
+        WebhookPayload {
+            id: 1,
+            event_id: "event".to_string(),
+            kind: kind.as_str().to_string(),
+            message: "Task finished".to_string(),
+            account: None,
+            created_at: at(0),
+        }
+    }
+
+    fn summary() -> TaskSummary {
+        let sale = sale();
+        let results = vec![
+            result(1, Some("A-hytti"), Some(2), 12),
+            result(2, None, None, 12),
+            result(3, Some("A-hytti"), Some(0), 13),
+        ];
+        let accounts = vec![account(1, "Alice"), account(2, "Bob"), account(3, "Carol")];
+        let events = vec![
+            event(TaskEventKind::Started, 0),
+            event(TaskEventKind::SaleOpened, 10),
+        ];
+
+        TaskSummary::build("event", Some(&sale), &results, &accounts, &events)
+    }
+
+    #[test]
+    fn builds_summary_of_what_was_reserved() {
+        let summary = summary();
+
+        assert_eq!(summary.event_name, "Sitsit <3 & more");
+        assert_eq!(
+            summary.reservations,
+            vec![ReservationSummary {
+                account: "Alice".to_string(),
+                variant: "A-hytti".to_string(),
+                quantity: 2,
+                price_per_item: Some(1250),
+            }]
+        );
+        assert_eq!(summary.empty_handed, vec!["Bob", "Carol"]);
+        assert_eq!(summary.latency, Some(Duration::seconds(2)));
+        assert_eq!(summary.tickets(), 2);
+        assert_eq!(summary.total_cents(), 2500);
+    }
+
+    #[test]
+    fn measures_latency_from_the_latest_run() {
+        let sale = sale();
+        let results = vec![
+            result(1, Some("A-hytti"), Some(2), 12),
+            result(1, Some("A-hytti"), Some(2), 105),
+        ];
+        let events = vec![
+            event(TaskEventKind::Started, 0),
+            event(TaskEventKind::SaleOpened, 10),
+            event(TaskEventKind::Started, 90),
+            event(TaskEventKind::SaleOpened, 100),
+        ];
+
+        let summary = TaskSummary::build("event", Some(&sale), &results, &[], &events);
+
+        assert_eq!(summary.latency, Some(Duration::seconds(5)));
+    }
+
+    #[test]
+    fn builds_summary_without_results() {
+        let summary = TaskSummary::build("event", None, &[], &[], &[]);
+
+        assert_eq!(summary.event_name, "event");
+        assert!(summary.reservations.is_empty());
+        assert!(summary.empty_handed.is_empty());
+        assert_eq!(summary.latency, None);
+        assert_eq!(summary.headline(), "Nothing reserved for event");
+        assert!(summary.lines().is_empty());
+    }
+
+    #[test]
+    fn renders_raw_as_the_payload() {
+        let payload = payload(TaskEventKind::Finished);
+
+        assert_eq!(
+            render(WebhookFormat::Raw, &payload, Some(&summary())),
+            json!(payload)
+        );
+    }
+
+    #[test]
+    fn renders_discord() {
+        let summary = summary();
+        let body = render(
+            WebhookFormat::Discord,
+            &payload(TaskEventKind::Finished),
+            Some(&summary),
+        );
+
+        assert_eq!(body["content"], summary.headline());
+        assert_eq!(body["embeds"][0]["title"], "finished");
+        assert_eq!(body["embeds"][0]["description"], summary.lines().join("\n"));
+        assert_eq!(body["embeds"][0]["color"], COLOR_SUCCESS);
+        assert_eq!(body["embeds"][0]["footer"]["text"], "event");
+    }
+
+    #[test]
+    fn renders_slack() {
+        let summary = summary();
+        let body = render(
+            WebhookFormat::Slack,
+            &payload(TaskEventKind::Finished),
+            Some(&summary),
+        );
+
+        assert_eq!(body["text"], summary.headline());
+        assert_eq!(
+            body["blocks"][0]["text"]["text"],
+            format!("*{}*", summary.headline())
+        );
+        assert_eq!(
+            body["blocks"][1]["text"]["text"],
+            summary.lines().join("\n")
+        );
+        assert_eq!(body["blocks"][2]["elements"][0]["text"], "finished · event");
+    }
+
+    #[test]
+    fn renders_matrix_with_escaped_html() {
+        let summary = summary();
+        let body = render(
+            WebhookFormat::Matrix,
+            &payload(TaskEventKind::Finished),
+            Some(&summary),
+        );
+
+        let headline = "Reserved 2 tickets for Sitsit <3 & more (12.50 €)";
+        assert_eq!(summary.headline(), headline);
+        assert_eq!(
+            body["text"],
+            format!("{}\n{}", headline, summary.lines().join("\n"))
+        );
+
+        let html = body["html"].as_str().unwrap();
+        assert!(html.starts_with(
+            "<strong>Reserved 2 tickets for Sitsit &lt;3 &amp; more (12.50 €)</strong><ul>"
+        ));
+        assert!(!html.contains("<3"));
+        assert_eq!(html.matches("<li>").count(), summary.lines().len());
+    }
+
+    #[test]
+    fn renders_matrix_without_a_summary() {
+        let body = render(
+            WebhookFormat::Matrix,
+            &payload(TaskEventKind::Started),
+            None,
+        );
+
+        assert_eq!(body["text"], "event: Task finished");
+        assert_eq!(body["html"], "<strong>event: Task finished</strong>");
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fang::{FangError, ToFangError};
+use once_cell::sync::OnceCell;
+use openssl::error::ErrorStack;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+
+static KEYRING_INSTANCE: OnceCell<Keyring> = OnceCell::new();
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// Keys are read from TOKEN_KEYS, or from the file pointed to by TOKEN_KEYS_FILE. Both hold a list
+// of `id:base64key` entries separated by commas or newlines. The first entry is the current key
+// used for new tokens, the rest are only kept around to unwrap tokens until they are rotated.
+pub fn initialize_keyring() {
+    let keyring = Keyring::from_env().expect("Failed to load token encryption keys");
+    set_keyring(keyring);
+}
+
+pub fn get_keyring() -> &'static Keyring {
+    KEYRING_INSTANCE.get().unwrap()
+}
+
+fn set_keyring(keyring: Keyring) {
+    let _ = KEYRING_INSTANCE.set(keyring);
+}
+
+#[derive(thiserror::Error, Debug, ToFangError)]
+pub enum CryptoError {
+    #[error("No token encryption keys configured, set TOKEN_KEYS or TOKEN_KEYS_FILE")]
+    NoKeys,
+
+    #[error("Invalid token encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("Unknown token encryption key: {0}")]
+    UnknownKey(String),
+
+    #[error("Malformed encrypted token")]
+    Malformed,
+
+    #[error("Token not yet encrypted, it is encrypted when a worker starts")]
+    NotEncrypted,
+
+    #[error("Could not read key file")]
+    KeyFile(#[from] std::io::Error),
+
+    #[error("OpenSSL error")]
+    OpenSSL(#[from] ErrorStack),
+}
+
+pub struct Keyring {
+    // Ordered, the first key is the current one
+    keys: Vec<(String, [u8; KEY_LEN])>,
+}
+
+impl Keyring {
+    pub fn from_env() -> Result<Self, CryptoError> {
+        let source = match env::var("TOKEN_KEYS") {
+            Ok(keys) => keys,
+            Err(_) => match env::var("TOKEN_KEYS_FILE") {
+                Ok(path) => fs::read_to_string(path)?,
+                Err(_) => return Err(CryptoError::NoKeys),
+            },
+        };
+
+        Self::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, CryptoError> {
+        let mut keys = Vec::new();
+
+        for entry in source.split(|c| c == ',' || c == '\n') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (id, encoded) = entry
+                .split_once(':')
+                .ok_or_else(|| CryptoError::InvalidKey(entry.to_string()))?;
+
+            let decoded = STANDARD
+                .decode(encoded)
+                .map_err(|_| CryptoError::InvalidKey(id.to_string()))?;
+            let key: [u8; KEY_LEN] = decoded
+                .try_into()
+                .map_err(|_| CryptoError::InvalidKey(id.to_string()))?;
+
+            keys.push((id.to_string(), key));
+        }
+
+        if keys.is_empty() {
+            return Err(CryptoError::NoKeys);
+        }
+
+        Ok(Self { keys })
+    }
+
+    pub fn current_id(&self) -> &str {
+        &self.keys[0].0
+    }
+
+    fn current(&self) -> (&str, &[u8; KEY_LEN]) {
+        let (id, key) = &self.keys[0];
+        (id, key)
+    }
+
+    fn key(&self, id: &str) -> Result<&[u8; KEY_LEN], CryptoError> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| CryptoError::UnknownKey(id.to_string()))
+    }
+}
+
+// A token encrypted with its own data key, which in turn is wrapped with a key from the keyring.
+// Rotating the keyring only requires rewrapping the data key.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SealedToken {
+    pub key_id: String,
+    pub wrapped_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl SealedToken {
+    pub fn seal(keyring: &Keyring, token: &str) -> Result<Self, CryptoError> {
+        let mut data_key = [0u8; KEY_LEN];
+        rand_bytes(&mut data_key)?;
+
+        let ciphertext = seal_bytes(&data_key, token.as_bytes(), &[])?;
+
+        let (key_id, key) = keyring.current();
+        let wrapped_key = seal_bytes(key, &data_key, key_id.as_bytes())?;
+
+        Ok(Self {
+            key_id: key_id.to_string(),
+            wrapped_key,
+            ciphertext,
+        })
+    }
+
+    pub fn open(&self, keyring: &Keyring) -> Result<String, CryptoError> {
+        let data_key = self.unwrap_key(keyring)?;
+        let token = open_bytes(&data_key, &self.ciphertext, &[])?;
+
+        String::from_utf8(token).map_err(|_| CryptoError::Malformed)
+    }
+
+    // Wraps the data key with the current key of the keyring, the ciphertext stays as is
+    pub fn rewrap(&self, keyring: &Keyring) -> Result<Self, CryptoError> {
+        let data_key = self.unwrap_key(keyring)?;
+
+        let (key_id, key) = keyring.current();
+        let wrapped_key = seal_bytes(key, &data_key, key_id.as_bytes())?;
+
+        Ok(Self {
+            key_id: key_id.to_string(),
+            wrapped_key,
+            ciphertext: self.ciphertext.clone(),
+        })
+    }
+
+    fn unwrap_key(&self, keyring: &Keyring) -> Result<Vec<u8>, CryptoError> {
+        let key = keyring.key(&self.key_id)?;
+        open_bytes(key, &self.wrapped_key, self.key_id.as_bytes())
+    }
+}
+
+// AES-256-GCM, laid out as nonce | ciphertext | tag
+fn seal_bytes(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        aad,
+        plaintext,
+        &mut tag,
+    )?;
+
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+
+    Ok(sealed)
+}
+
+fn open_bytes(key: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(CryptoError::Malformed);
+    }
+
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    Ok(decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(keys: &[(&str, u8)]) -> Keyring {
+        let source: Vec<String> = keys
+            .iter()
+            .map(|(id, byte)| format!("{}:{}", id, STANDARD.encode([*byte; KEY_LEN])))
+            .collect();
+
+        Keyring::parse(&source.join(",")).unwrap()
+    }
+
+    #[test]
+    fn opens_what_it_sealed() {
+        let keyring = keyring(&[("a", 1)]);
+        let sealed = SealedToken::seal(&keyring, "token").unwrap();
+
+        assert_eq!(sealed.key_id, "a");
+        assert_eq!(sealed.open(&keyring).unwrap(), "token");
+    }
+
+    #[test]
+    fn rewraps_with_the_current_key() {
+        let sealed = SealedToken::seal(&keyring(&[("a", 1)]), "token").unwrap();
+
+        // Rotated, "b" is the current key and "a" is only kept for unwrapping
+        let rotated = keyring(&[("b", 2), ("a", 1)]);
+        let rewrapped = sealed.rewrap(&rotated).unwrap();
+
+        assert_eq!(rewrapped.key_id, "b");
+        assert_eq!(rewrapped.ciphertext, sealed.ciphertext);
+        assert_eq!(rewrapped.open(&keyring(&[("b", 2)])).unwrap(), "token");
+    }
+
+    #[test]
+    fn fails_to_open_with_the_wrong_key() {
+        let sealed = SealedToken::seal(&keyring(&[("a", 1)]), "token").unwrap();
+
+        assert!(matches!(
+            sealed.open(&keyring(&[("a", 2)])),
+            Err(CryptoError::OpenSSL(_))
+        ));
+        assert!(matches!(
+            sealed.open(&keyring(&[("b", 1)])),
+            Err(CryptoError::UnknownKey(_))
+        ));
+    }
+}
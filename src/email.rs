@@ -0,0 +1,539 @@
+use chrono::{Duration, Utc};
+use fang::async_trait;
+use fang::asynk::async_queue::AsyncQueueable;
+use fang::serde::{Deserialize, Serialize};
+use fang::typetag;
+use fang::AsyncRunnable;
+use fang::Scheduled;
+use fang::{FangError, ToFangError};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use once_cell::sync::OnceCell;
+use std::env;
+use uuid::Uuid;
+
+use crate::account::KideAccount;
+use crate::chat::{escape_html, TaskSummary};
+use crate::db::{get_db_manager, DBError};
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::queue::get_dispatch_queue;
+use crate::secret::Secret;
+use crate::task::TaskKey;
+use crate::user::User;
+
+// Accounts whose token expires within this many days are warned about
+const TOKEN_EXPIRY_WARNING_DAYS: i64 = 3;
+// Every morning, fang's cron patterns include seconds
+const TOKEN_EXPIRY_CHECK_SCHEDULE: &str = "0 0 7 * * *";
+
+static MAILER_INSTANCE: OnceCell<Mailer> = OnceCell::new();
+
+#[derive(thiserror::Error, Debug, ToFangError)]
+pub enum EmailError {
+    #[error("Database error")]
+    DBError(#[from] DBError),
+
+    #[error("Invalid SMTP configuration: {0}")]
+    Config(String),
+
+    #[error("Invalid address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+
+    #[error("Could not build email: {0}")]
+    Message(#[from] lettre::error::Error),
+
+    #[error("Could not send email: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
+
+// Read from SMTP_HOST, SMTP_PORT, SMTP_STARTTLS, SMTP_USERNAME, SMTP_PASSWORD and SMTP_FROM.
+// STARTTLS can only be turned off for local sinks, credentials are never sent in plaintext.
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub starttls: bool,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    pub from: String,
+}
+
+impl SmtpConfig {
+    // None if email isn't configured at all
+    pub fn from_env() -> Result<Option<Self>, EmailError> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    // Same as `from_env`, with the variables looked up through `var`
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, EmailError> {
+        let host = match var("SMTP_HOST") {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        let port = match var("SMTP_PORT") {
+            Some(port) => port
+                .parse()
+                .map_err(|_| EmailError::Config(format!("SMTP_PORT is not a port: {}", port)))?,
+            None => 587,
+        };
+
+        let starttls = var("SMTP_STARTTLS")
+            .map(|value| value != "false" && value != "0")
+            .unwrap_or(true);
+
+        let username = var("SMTP_USERNAME");
+        let password = var("SMTP_PASSWORD").map(Secret::new);
+        if !starttls && username.is_some() {
+            return Err(EmailError::Config(
+                "SMTP credentials require STARTTLS".to_string(),
+            ));
+        }
+
+        let from = var("SMTP_FROM")
+            .ok_or_else(|| EmailError::Config("SMTP_FROM must be set".to_string()))?;
+
+        Ok(Some(Self {
+            host,
+            port,
+            starttls,
+            username,
+            password,
+            from,
+        }))
+    }
+}
+
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn new(config: SmtpConfig) -> Result<Self, EmailError> {
+        let builder = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        };
+
+        let mut builder = builder.port(config.port);
+        if let Some(username) = config.username {
+            let password = config
+                .password
+                .map(|password| password.expose().to_string())
+                .unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse()?,
+        })
+    }
+
+    pub async fn send(&self, to: &str, email: &Email) -> Result<(), EmailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(&email.subject)
+            .multipart(MultiPart::alternative_plain_html(
+                email.text.clone(),
+                email.html.clone(),
+            ))?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+// Email is optional, without SMTP_HOST nothing is sent
+pub fn initialize_mailer() {
+    let config = match SmtpConfig::from_env().expect("Invalid SMTP configuration") {
+        Some(config) => config,
+        None => {
+            log::info!("SMTP_HOST not set, email notifications are disabled");
+            return;
+        }
+    };
+
+    let mailer = Mailer::new(config).expect("Failed to set up SMTP");
+    let _ = MAILER_INSTANCE.set(mailer);
+}
+
+pub fn get_mailer() -> Option<&'static Mailer> {
+    MAILER_INSTANCE.get()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+impl Email {
+    pub fn task_outcome(summary: &TaskSummary, failed: bool, message: &str) -> Self {
+        let lines = summary.lines();
+        let status = if failed {
+            format!("The task failed: {}", message)
+        } else {
+            "The task finished.".to_string()
+        };
+
+        let text = format!(
+            "{}\n\n{}\n\n{}\n\nEvent: {}\n",
+            summary.headline(),
+            status,
+            lines.join("\n"),
+            summary.event_id
+        );
+
+        let items: String = lines
+            .iter()
+            .map(|line| format!("<li>{}</li>", escape_html(line)))
+            .collect();
+        let html = format!(
+            "<h2>{}</h2><p>{}</p><ul>{}</ul><p><small>Event: {}</small></p>",
+            escape_html(&summary.headline()),
+            escape_html(&status),
+            items,
+            escape_html(&summary.event_id)
+        );
+
+        Self {
+            subject: summary.headline(),
+            text,
+            html,
+        }
+    }
+
+    pub fn token_expiry(accounts: &[KideAccount]) -> Self {
+        let lines: Vec<String> = accounts
+            .iter()
+            .map(|account| match account.token_expires_at {
+                Some(expires_at) if expires_at < Utc::now() => {
+                    format!("{}: expired at {}", account.name, expires_at)
+                }
+                Some(expires_at) => format!("{}: expires at {}", account.name, expires_at),
+                None => format!("{}: unknown expiry", account.name),
+            })
+            .collect();
+
+        let intro = "Tasks can't reserve with these accounts until their tokens are replaced.";
+        let text = format!("{}\n\n{}\n", intro, lines.join("\n"));
+
+        let items: String = lines
+            .iter()
+            .map(|line| format!("<li>{}</li>", escape_html(line)))
+            .collect();
+        let html = format!("<p>{}</p><ul>{}</ul>", escape_html(intro), items);
+
+        Self {
+            subject: format!("{} Kide tokens are about to expire", accounts.len()),
+            text,
+            html,
+        }
+    }
+}
+
+// Who hears about a task: its owner, or the admins for tasks without one
+async fn task_recipients(task: &TaskKey) -> Result<Vec<String>, DBError> {
+    let db_manager = get_db_manager();
+    let rows = db_manager
+        .query(
+            "SELECT email FROM users WHERE email IS NOT NULL \
+             AND (uuid = $1 OR ($1::uuid IS NULL AND role = 'admin'))",
+            &[&task.owner_id],
+        )
+        .await?;
+
+    let mut recipients = Vec::new();
+    for row in rows {
+        recipients.push(row.try_get("email")?);
+    }
+
+    Ok(recipients)
+}
+
+// Queues an email about the outcome of the task to everyone that should hear about it. Like the
+// webhooks this never fails the task.
+pub async fn dispatch(event: &TaskEvent) {
+    if !matches!(event.kind, TaskEventKind::Finished | TaskEventKind::Failed) {
+        return;
+    }
+
+    let mut queue = match get_dispatch_queue() {
+        Some(queue) => queue,
+        None => return,
+    };
+
+    let recipients = match task_recipients(&event.key()).await {
+        Ok(recipients) => recipients,
+        Err(e) => {
+            log::warn!("Failed to fetch recipients for {}: {}", event.event_id, e);
+            return;
+        }
+    };
+
+    for to in recipients {
+        let task = TaskOutcomeEmailTask {
+            to,
+            event_id: event.event_id.clone(),
+            owner_id: event.owner_id,
+            failed: event.kind == TaskEventKind::Failed,
+            message: event.message.clone(),
+        };
+
+        if let Err(e) = queue.insert_task(&task as &dyn AsyncRunnable).await {
+            log::warn!("Failed to queue an email about {}: {:?}", event.event_id, e);
+        }
+    }
+}
+
+// Mails a summary of how a task ended
+#[derive(Default, Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct TaskOutcomeEmailTask {
+    pub to: String,
+    pub event_id: String,
+    #[serde(default)]
+    pub owner_id: Option<Uuid>,
+    pub failed: bool,
+    pub message: String,
+}
+
+#[async_trait]
+#[typetag::serde]
+impl AsyncRunnable for TaskOutcomeEmailTask {
+    async fn run(&self, _queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+        let mailer = match get_mailer() {
+            Some(mailer) => mailer,
+            None => return Ok(()),
+        };
+
+        let summary = TaskSummary::load(&TaskKey::new(self.event_id.clone(), self.owner_id)).await;
+        let email = Email::task_outcome(&summary, self.failed, &self.message);
+
+        mailer.send(&self.to, &email).await?;
+        Ok(())
+    }
+
+    fn uniq(&self) -> bool {
+        true
+    }
+
+    fn max_retries(&self) -> i32 {
+        5
+    }
+
+    fn task_type(&self) -> String {
+        "common".to_string()
+    }
+}
+
+// Warns every user with an email about their accounts whose tokens are about to expire
+#[derive(Default, Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct TokenExpiryCheckTask {}
+
+#[async_trait]
+#[typetag::serde]
+impl AsyncRunnable for TokenExpiryCheckTask {
+    async fn run(&self, _queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+        let mailer = match get_mailer() {
+            Some(mailer) => mailer,
+            None => return Ok(()),
+        };
+
+        let cutoff = Utc::now() + Duration::days(TOKEN_EXPIRY_WARNING_DAYS);
+
+        for user in User::all().await? {
+            let to = match &user.email {
+                Some(to) => to,
+                None => continue,
+            };
+
+            let expiring: Vec<KideAccount> = KideAccount::all(Some(user.uuid))
+                .await?
+                .into_iter()
+                .filter(|account| {
+                    matches!(account.token_expires_at, Some(expires_at) if expires_at < cutoff)
+                })
+                .collect();
+
+            if expiring.is_empty() {
+                continue;
+            }
+
+            // One user's broken address shouldn't keep the others from being warned
+            if let Err(e) = mailer.send(to, &Email::token_expiry(&expiring)).await {
+                log::warn!("Failed to warn {} about expiring tokens: {}", user.name, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cron(&self) -> Option<Scheduled> {
+        Some(Scheduled::CronPattern(
+            TOKEN_EXPIRY_CHECK_SCHEDULE.to_string(),
+        ))
+    }
+
+    fn uniq(&self) -> bool {
+        true
+    }
+
+    fn task_type(&self) -> String {
+        "common".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ReservationSummary;
+    use std::collections::HashMap;
+
+    fn summary() -> TaskSummary {
+        TaskSummary {
+            event_id: "event".to_string(),
+            event_name: "Sitsit <3 & more".to_string(),
+            reservations: vec![ReservationSummary {
+                account: "Alice".to_string(),
+                variant: "A-hytti".to_string(),
+                quantity: 2,
+                price_per_item: Some(1250),
+            }],
+            empty_handed: vec!["Bob".to_string()],
+            latency: None,
+        }
+    }
+
+    fn config(vars: &[(&str, &str)]) -> Result<Option<SmtpConfig>, EmailError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        SmtpConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn task_outcome_has_plain_and_html_parts() {
+        let email = Email::task_outcome(&summary(), false, "");
+
+        assert_eq!(
+            email.subject,
+            "Reserved 2 tickets for Sitsit <3 & more (25.00 €)"
+        );
+        assert_eq!(
+            email.text,
+            "Reserved 2 tickets for Sitsit <3 & more (25.00 €)\n\nThe task finished.\n\n\
+             Alice: 2 × A-hytti à 12.50 €\nNothing for: Bob\n\nEvent: event\n"
+        );
+        assert_eq!(
+            email.html,
+            "<h2>Reserved 2 tickets for Sitsit &lt;3 &amp; more (25.00 €)</h2>\
+             <p>The task finished.</p>\
+             <ul><li>Alice: 2 × A-hytti à 12.50 €</li><li>Nothing for: Bob</li></ul>\
+             <p><small>Event: event</small></p>"
+        );
+    }
+
+    #[test]
+    fn task_outcome_escapes_the_failure() {
+        let email = Email::task_outcome(&summary(), true, "<script>");
+
+        assert!(email.text.contains("The task failed: <script>"));
+        assert!(email
+            .html
+            .contains("<p>The task failed: &lt;script&gt;</p>"));
+        assert!(!email.html.contains("<script>"));
+    }
+
+    #[test]
+    fn token_expiry_lists_accounts() {
+        let accounts = vec![
+            KideAccount {
+                name: "<b>Alice</b>".to_string(),
+                token_expires_at: None,
+                ..Default::default()
+            },
+            KideAccount {
+                name: "Bob".to_string(),
+                token_expires_at: Some(Utc::now() - Duration::days(1)),
+                ..Default::default()
+            },
+        ];
+
+        let email = Email::token_expiry(&accounts);
+
+        assert_eq!(email.subject, "2 Kide tokens are about to expire");
+        assert!(email.text.contains("<b>Alice</b>: unknown expiry\n"));
+        assert!(email.text.contains("Bob: expired at "));
+        assert!(email
+            .html
+            .contains("<li>&lt;b&gt;Alice&lt;/b&gt;: unknown expiry</li>"));
+        assert!(!email.html.contains("<b>"));
+    }
+
+    #[test]
+    fn smtp_is_optional() {
+        assert!(config(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn smtp_defaults_to_starttls_on_587() {
+        let config = config(&[("SMTP_HOST", "smtp.example.com"), ("SMTP_FROM", "a@b.c")])
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(config.port, 587);
+        assert!(config.starttls);
+    }
+
+    #[test]
+    fn smtp_credentials_require_starttls() {
+        let result = config(&[
+            ("SMTP_HOST", "localhost"),
+            ("SMTP_STARTTLS", "false"),
+            ("SMTP_USERNAME", "user"),
+            ("SMTP_PASSWORD", "password"),
+            ("SMTP_FROM", "a@b.c"),
+        ]);
+
+        assert!(matches!(result, Err(EmailError::Config(_))));
+    }
+
+    #[test]
+    fn smtp_allows_plaintext_without_credentials() {
+        let config = config(&[
+            ("SMTP_HOST", "localhost"),
+            ("SMTP_PORT", "1025"),
+            ("SMTP_STARTTLS", "0"),
+            ("SMTP_FROM", "a@b.c"),
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(config.port, 1025);
+        assert!(!config.starttls);
+    }
+
+    #[test]
+    fn smtp_rejects_bad_ports() {
+        for port in ["smtp", "70000", "-1"] {
+            let result = config(&[
+                ("SMTP_HOST", "localhost"),
+                ("SMTP_PORT", port),
+                ("SMTP_FROM", "a@b.c"),
+            ]);
+
+            assert!(matches!(result, Err(EmailError::Config(_))));
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
+use juniper::{GraphQLEnum, GraphQLObject};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::db::{get_db_manager, DBError};
+use crate::notify::{subscribe, Notification};
+use crate::task::TaskKey;
+use crate::{email, webhook};
+
+// With a notification listener running, subscribers are woken up by notifications and only check
+// the table every WATCH_INTERVAL in case one was missed. Without one they poll every POLL_INTERVAL.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, GraphQLEnum)]
+#[graphql(description = "What happened in a task")]
+pub enum TaskEventKind {
+    Scheduled,
+    Started,
+    Waiting,
+    Polling,
+    SaleOpened,
+    VariantChosen,
+    ReservationSucceeded,
+    ReservationFailed,
+    GroupCompleted,
+    GroupReleased,
+    Restocked,
+    HoldExpiring,
+    Finished,
+    Failed,
+}
+
+impl TaskEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskEventKind::Scheduled => "scheduled",
+            TaskEventKind::Started => "started",
+            TaskEventKind::Waiting => "waiting",
+            TaskEventKind::Polling => "polling",
+            TaskEventKind::SaleOpened => "sale_opened",
+            TaskEventKind::VariantChosen => "variant_chosen",
+            TaskEventKind::ReservationSucceeded => "reservation_succeeded",
+            TaskEventKind::ReservationFailed => "reservation_failed",
+            TaskEventKind::GroupCompleted => "group_completed",
+            TaskEventKind::GroupReleased => "group_released",
+            TaskEventKind::Restocked => "restocked",
+            TaskEventKind::HoldExpiring => "hold_expiring",
+            TaskEventKind::Finished => "finished",
+            TaskEventKind::Failed => "failed",
+        }
+    }
+
+    pub fn from_db(kind: &str) -> Option<Self> {
+        match kind {
+            "scheduled" => Some(TaskEventKind::Scheduled),
+            "started" => Some(TaskEventKind::Started),
+            "waiting" => Some(TaskEventKind::Waiting),
+            "polling" => Some(TaskEventKind::Polling),
+            "sale_opened" => Some(TaskEventKind::SaleOpened),
+            "variant_chosen" => Some(TaskEventKind::VariantChosen),
+            "reservation_succeeded" => Some(TaskEventKind::ReservationSucceeded),
+            "reservation_failed" => Some(TaskEventKind::ReservationFailed),
+            "group_completed" => Some(TaskEventKind::GroupCompleted),
+            "group_released" => Some(TaskEventKind::GroupReleased),
+            "restocked" => Some(TaskEventKind::Restocked),
+            "hold_expiring" => Some(TaskEventKind::HoldExpiring),
+            "finished" => Some(TaskEventKind::Finished),
+            "failed" => Some(TaskEventKind::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, GraphQLObject)]
+#[graphql(description = "Progress of a task, emitted by the worker running it")]
+pub struct TaskEvent {
+    #[graphql(ignore)]
+    pub id: i64,
+    pub event_id: String,
+    // Owner of the task that emitted the event, the event belongs to nobody else's task
+    #[graphql(ignore)]
+    pub owner_id: Option<Uuid>,
+    pub kind: TaskEventKind,
+    pub message: String,
+    pub account: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'a> TryFrom<&'a Row> for TaskEvent {
+    type Error = DBError;
+
+    fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        let kind: String = row.try_get("kind")?;
+
+        Ok(Self {
+            id: row.try_get("id")?,
+            event_id: row.try_get("event_id")?,
+            owner_id: row.try_get("owner_uuid")?,
+            // Unknown kinds can only come from a newer worker, show them as plain progress
+            kind: TaskEventKind::from_db(&kind).unwrap_or(TaskEventKind::Polling),
+            message: row.try_get("message")?,
+            account: row.try_get("account_uuid")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+impl TaskEvent {
+    pub fn key(&self) -> TaskKey {
+        TaskKey::new(self.event_id.clone(), self.owner_id)
+    }
+
+    // Records an event for a task and hands it to the notifiers. Failing to record progress must
+    // never fail the task itself, so errors are only logged.
+    pub async fn emit(
+        task: &TaskKey,
+        kind: TaskEventKind,
+        message: impl Into<String>,
+        account: Option<Uuid>,
+    ) {
+        let event_id = task.event_id.as_str();
+        let message = message.into();
+        log::debug!("[{}] {}: {}", event_id, kind.as_str(), message);
+
+        let db_manager = get_db_manager();
+        let result = db_manager
+            .query_one(
+                "INSERT INTO task_events (event_id, owner_uuid, kind, message, account_uuid) \
+                 VALUES ($1, $2, $3, $4, $5) RETURNING *",
+                &[
+                    &event_id,
+                    &task.owner_id,
+                    &kind.as_str(),
+                    &message,
+                    &account,
+                ],
+            )
+            .await;
+
+        let row = match result {
+            Ok(row) => row,
+            Err(e) => {
+                log::warn!("Failed to record task event for {}: {}", event_id, e);
+                return;
+            }
+        };
+
+        // Reservations don't wait for the webhooks to be looked up
+        match TaskEvent::try_from(&row) {
+            Ok(event) => {
+                tokio::spawn(async move {
+                    webhook::dispatch(&event).await;
+                    email::dispatch(&event).await;
+                });
+            }
+            Err(e) => log::warn!("Failed to read back task event for {}: {}", event_id, e),
+        }
+    }
+
+    // Events of a task newer than `after`, oldest first
+    pub async fn since(task: &TaskKey, after: i64) -> Result<Vec<TaskEvent>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM task_events WHERE event_id = $1 \
+                 AND owner_uuid IS NOT DISTINCT FROM $2 AND id > $3 ORDER BY id",
+                &[&task.event_id, &task.owner_id, &after],
+            )
+            .await?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            events.push(TaskEvent::try_from(&row)?);
+        }
+
+        Ok(events)
+    }
+
+    pub async fn latest_id(task: &TaskKey) -> Result<i64, DBError> {
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_one(
+                "SELECT COALESCE(MAX(id), 0) AS id FROM task_events WHERE event_id = $1 \
+                 AND owner_uuid IS NOT DISTINCT FROM $2",
+                &[&task.event_id, &task.owner_id],
+            )
+            .await?;
+
+        Ok(row.try_get("id")?)
+    }
+
+    // Streams the events of a task as they are recorded, starting after `after`. The stream ends
+    // after yielding a database error.
+    pub fn watch(task: TaskKey, after: i64) -> impl Stream<Item = Result<TaskEvent, DBError>> {
+        let state = WatchState {
+            task,
+            after,
+            pending: VecDeque::new(),
+            failed: false,
+            notifications: subscribe(),
+        };
+
+        stream::unfold(state, |mut state| async move {
+            if state.failed {
+                return None;
+            }
+
+            loop {
+                if let Some(event) = state.pending.pop_front() {
+                    return Some((Ok(event), state));
+                }
+
+                match TaskEvent::since(&state.task, state.after).await {
+                    Ok(events) if events.is_empty() => state.wait().await,
+                    Ok(events) => {
+                        state.after = events.last().map(|event| event.id).unwrap_or(state.after);
+                        state.pending.extend(events);
+                    }
+                    Err(e) => {
+                        state.failed = true;
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+        })
+    }
+}
+
+struct WatchState {
+    task: TaskKey,
+    after: i64,
+    pending: VecDeque<TaskEvent>,
+    failed: bool,
+    notifications: Option<broadcast::Receiver<Notification>>,
+}
+
+impl WatchState {
+    // Waits until an event for this task is announced, or until it's time to check anyway
+    async fn wait(&mut self) {
+        let notifications = match &mut self.notifications {
+            Some(notifications) => notifications,
+            None => return tokio::time::sleep(POLL_INTERVAL).await,
+        };
+
+        let task = &self.task;
+        let after = self.after;
+        let announced = async {
+            loop {
+                match notifications.recv().await {
+                    Ok(Notification::Event(event))
+                        if event.event_id == task.event_id
+                            && event.owner_id == task.owner_id
+                            && event.id > after =>
+                    {
+                        return
+                    }
+                    Ok(_) => {}
+                    // Missed some notifications, just check the table
+                    Err(broadcast::error::RecvError::Lagged(_)) => return,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return tokio::time::sleep(POLL_INTERVAL).await
+                    }
+                }
+            }
+        };
+
+        let _ = tokio::time::timeout(WATCH_INTERVAL, announced).await;
+    }
+}
@@ -1,14 +1,20 @@
-use crystal::account::AccountIDList;
+use crystal::account::{AccountIDList, Eligibility, KideAccount};
+use crystal::auth::ApiKey;
+use crystal::crypto::initialize_keyring;
 use crystal::db::initialize_db_manager;
+use crystal::email::{get_mailer, initialize_mailer, Email};
 use crystal::queue::connect_to_queue;
-use crystal::request::Client;
-use crystal::task::ScalpingTask;
+use crystal::request::{event_id_from_url, get_client};
+use crystal::secret::Secret;
+use crystal::task::{ScalpingTask, TaskKey};
+use crystal::user::{Role, User};
+use crystal::webhook::Webhook;
 
 use dotenvy::dotenv;
 use fang::asynk::async_queue::AsyncQueueable;
 use fang::AsyncRunnable;
 use std::env;
-use uuid::uuid;
+use uuid::{uuid, Uuid};
 
 use clap::{Parser, Subcommand};
 
@@ -35,7 +41,75 @@ enum Commands {
 
         // JWT Token for account
         token: String,
+
+        // User owning the account
+        #[clap(short, long)]
+        owner: Option<Uuid>,
+
+        #[clap(long)]
+        student_card: bool,
+
+        // HAKA authentication is linked
+        #[clap(long)]
+        haka: bool,
+
+        // Membership id, can be repeated
+        #[clap(long)]
+        membership: Vec<String>,
+
+        // Most the account may spend on everything it reserves in a task, in cents
+        #[clap(long)]
+        budget: Option<i32>,
+    },
+    // Manage users of the GraphQL server
+    User {
+        #[command(subcommand)]
+        command: UserCommands,
+    },
+    // Rewrap all tokens with the current key, run after adding a new key to TOKEN_KEYS
+    RotateKeys,
+    // Manage API keys for the GraphQL server
+    ApiKey {
+        #[command(subcommand)]
+        command: ApiKeyCommands,
+    },
+    // Send a test email with the SMTP_* settings, e.g. to a local sink
+    TestEmail {
+        to: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserCommands {
+    Add {
+        name: String,
+
+        // Admins see and manage everything
+        #[clap(short, long)]
+        admin: bool,
+    },
+    List,
+    // Where task outcomes and token warnings are mailed, clears it if no email is given
+    SetEmail {
+        user: Uuid,
+        email: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ApiKeyCommands {
+    // Issue a new key, it is printed only once
+    Issue {
+        // User the key authenticates as
+        user: Uuid,
+
+        // Who or what the key is for
+        name: String,
+    },
+    Revoke {
+        id: Uuid,
     },
+    List,
 }
 
 #[tokio::main]
@@ -50,10 +124,11 @@ async fn main() {
 
     // Initialize DB Pool for crystal operations
     initialize_db_manager(database_url.clone()).await;
+    initialize_keyring();
 
     match cli.command {
         Commands::Task { url, direct } => {
-            let event_id = url.split("/").last().unwrap();
+            let event_id = event_id_from_url(&url).expect("Invalid event url");
             let account_uuids = vec![
                 uuid!("58ce05ca-5c43-44d5-a5a7-a4b5a727b6ad"),
                 uuid!("c749d6d4-3ede-44b1-b4e6-20b1f52b6a2c"),
@@ -66,16 +141,100 @@ async fn main() {
                 add_task(event_id.to_string(), account_uuids, database_url).await;
             }
         }
-        Commands::Account { name, token } => {
-            crystal::account::KideAccount::create(name, token)
+        Commands::Account {
+            name,
+            token,
+            owner,
+            student_card,
+            haka,
+            membership,
+            budget,
+        } => {
+            let eligibility = Eligibility {
+                has_student_card: student_card,
+                has_haka: haka,
+                membership_ids: membership,
+                budget,
+            };
+
+            KideAccount::create(name, Secret::new(token), owner, eligibility)
                 .await
                 .unwrap();
         }
+        Commands::User { command } => run_user_command(command).await,
+        Commands::RotateKeys => {
+            let rotated = KideAccount::rotate_token_keys().await.unwrap();
+            log::info!("Rotated {} tokens", rotated);
+
+            let rotated = Webhook::rotate_secret_keys().await.unwrap();
+            log::info!("Rotated {} webhook secrets", rotated);
+        }
+        Commands::ApiKey { command } => run_api_key_command(command).await,
+        Commands::TestEmail { to } => {
+            initialize_mailer();
+            let mailer = get_mailer().expect("SMTP_HOST must be set");
+
+            let email = Email {
+                subject: "Crystal test email".to_string(),
+                text: "Email notifications are working.".to_string(),
+                html: "<p>Email notifications are working.</p>".to_string(),
+            };
+            mailer.send(&to, &email).await.unwrap();
+            println!("Sent a test email to {}", to);
+        }
+    }
+}
+
+async fn run_user_command(command: UserCommands) {
+    match command {
+        UserCommands::Add { name, admin } => {
+            let role = if admin { Role::Admin } else { Role::User };
+            let user = User::create(name, role).await.unwrap();
+            println!("Added user {} ({})", user.uuid, user.name);
+        }
+        UserCommands::List => {
+            for user in User::all().await.unwrap() {
+                println!("{} {} ({})", user.uuid, user.name, user.role.as_str());
+            }
+        }
+        UserCommands::SetEmail { user, email } => {
+            let mut user = User::from_uuid(user).await.unwrap().expect("No such user");
+            user.set_email(email).await.unwrap();
+            println!("Updated the email of {}", user.name);
+        }
+    }
+}
+
+async fn run_api_key_command(command: ApiKeyCommands) {
+    match command {
+        ApiKeyCommands::Issue { user, name } => {
+            let (api_key, key) = ApiKey::issue(user, name).await.unwrap();
+            println!("Issued key {} for {}", api_key.uuid, api_key.name);
+            println!("{}", key.expose());
+        }
+        ApiKeyCommands::Revoke { id } => {
+            if ApiKey::revoke(id).await.unwrap() {
+                println!("Revoked key {}", id);
+            } else {
+                println!("No active key {}", id);
+            }
+        }
+        ApiKeyCommands::List => {
+            for api_key in ApiKey::all().await.unwrap() {
+                let status = match api_key.revoked_at {
+                    Some(revoked_at) => format!("revoked {}", revoked_at),
+                    None => "active".to_string(),
+                };
+                println!("{} {} ({})", api_key.uuid, api_key.name, status);
+            }
+        }
     }
 }
 
 async fn run_task(event_id: String, account_ids: AccountIDList) {
-    crystal::scalp::scalp(event_id.to_string(), account_ids, Default::default())
+    // Reminders need the queue, direct runs go without them
+    let task = TaskKey::new(event_id, None);
+    crystal::scalp::scalp(task, account_ids, Default::default(), None)
         .await
         .unwrap();
 }
@@ -86,8 +245,12 @@ async fn add_task(event_id: String, account_ids: AccountIDList, database_url: St
     log::info!("Queue connected...");
 
     // Fetch event details
-    let client = Client::new();
-    let sale_client = client.product(event_id.to_string()).await.unwrap();
+    let sale_client = get_client().product(event_id.to_string()).await.unwrap();
+
+    if ScalpingTask::exists(&event_id, None).await.unwrap() {
+        log::error!("There already is a task for event {}", event_id);
+        return;
+    }
 
     // Queue new task for workers
     let task = ScalpingTask::new(
@@ -95,6 +258,7 @@ async fn add_task(event_id: String, account_ids: AccountIDList, database_url: St
         account_ids,
         sale_client.sale.product.date_sales_from,
         Default::default(),
+        None,
     );
 
     queue
@@ -1,29 +1,86 @@
 use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
 use juniper::{
-    graphql_object, EmptySubscription, FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject,
+    graphql_object, graphql_subscription, FieldError, FieldResult, GraphQLEnum, GraphQLInputObject,
+    GraphQLObject,
 };
+use std::pin::Pin;
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::RwLock;
+use tokio_postgres::Row;
 use uuid::Uuid;
 
 use fang::asynk::async_queue::AsyncQueueable;
 use fang::AsyncRunnable;
 use fang::FangTaskState;
 
-use crate::account::KideAccount;
-use crate::db::get_db_manager;
+use crate::account::{Eligibility, KideAccount};
+use crate::api::Variant;
+use crate::chat::WebhookFormat;
+use crate::db::{get_db_manager, DBError};
+use crate::event::{TaskEvent, TaskEventKind};
 use crate::queue::Queue;
-use crate::request::Client;
-use crate::task::{ScalpingTask, TaskOptions};
+use crate::request::{event_id_from_url, get_client};
+use crate::result::TaskResult;
+use crate::sale::Sale;
+use crate::secret::Secret;
+use crate::strategy::{ScoreBreakdown, TicketPriorityStrategy};
+use crate::task::{ScalpingTask, TaskKey, TaskOptions};
+use crate::user::User;
+use crate::validation::{validate_task, ValidationReport};
+use crate::webhook::{Webhook, WebhookDelivery, WebhookDeliveryTask, WebhookPayload};
+
+// The runs a deleted task left scheduled, by its event ($1) and owner ($2). Hold reminders only
+// know their result, the event comes from the result.
+const DELETE_PENDING_RUNS: &str = "DELETE FROM fang_tasks WHERE state = 'new' \
+     AND metadata->>'ownerId' IS NOT DISTINCT FROM $2 \
+     AND ((metadata->>'type' = 'VariantRoundTask' AND metadata->>'eventId' = $1) \
+     OR (metadata->>'type' = 'HoldReminderTask' AND (metadata->>'resultId')::bigint IN \
+     (SELECT id FROM task_results WHERE event_id = $1 \
+     AND owner_uuid::text IS NOT DISTINCT FROM $2)))";
 
 // ---- Context ----
 
+// Built for every request, `user` is the owner of the API key the request was made with
 pub struct Context {
-    pub queue: RwLock<Queue>,
+    pub queue: Arc<RwLock<Queue>>,
+    pub user: User,
 }
 
 impl juniper::Context for Context {}
 
+impl Context {
+    // Owner id as stored in the task metadata, None lets admins see every task
+    fn task_scope(&self) -> Option<String> {
+        self.user.scope().map(|uuid| uuid.to_string())
+    }
+
+    // Accounts of other users are reported as missing instead of forbidden
+    async fn ensure_accounts(&self, account_ids: &[Uuid]) -> Result<(), ApiError> {
+        for account_id in account_ids {
+            let owner = KideAccount::from_uuid(*account_id)
+                .await?
+                .map(|account| account.owner_uuid);
+
+            match owner {
+                Some(owner) if self.user.can_access(owner) => {}
+                _ => return Err(ApiError::KideAccountNotFound(*account_id)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Webhooks of other users are reported as missing instead of forbidden
+    async fn ensure_webhook(&self, uuid: Uuid) -> Result<Webhook, ApiError> {
+        match Webhook::from_uuid(uuid).await? {
+            Some(webhook) if self.user.can_access(webhook.owner_uuid) => Ok(webhook),
+            _ => Err(ApiError::WebhookNotFound(uuid)),
+        }
+    }
+}
+
 // ---- Enums ----
 
 #[derive(Debug, GraphQLEnum)]
@@ -55,7 +112,23 @@ pub enum ApiError {
     #[error("Kide account not found: {0}")]
     KideAccountNotFound(Uuid),
     #[error("Task not found: {0}")]
-    TaskNotFound(String),
+    TaskNotFound(Uuid),
+    #[error("You already have a task for event {0}")]
+    TaskExists(String),
+    #[error("Event not found: {0}")]
+    EventNotFound(String),
+    #[error("Either an event id or an url is required")]
+    EventIdRequired,
+    #[error("Invalid target name: {0}")]
+    InvalidTargetName(#[from] regex::Error),
+    #[error("Webhook not found: {0}")]
+    WebhookNotFound(Uuid),
+    #[error("Invalid webhook url: {0}")]
+    InvalidWebhookUrl(String),
+    #[error("Invalid email: {0}")]
+    InvalidEmail(String),
+    #[error("Only admins can do this")]
+    Forbidden,
     #[error("Database error: {0}")]
     DBError(#[from] crate::db::DBError),
 }
@@ -66,35 +139,262 @@ pub enum ApiError {
 #[derive(GraphQLObject)]
 #[graphql(description = "A task")]
 struct Task {
+    // Tasks are addressed by id, every owner can have a task for the same event
+    id: Uuid,
     event_id: String,
+    owner_id: Option<Uuid>,
     accounts: Vec<KideAccount>,
     sale_start: DateTime<Utc>,
     state: TaskState,
     options: TaskOptions,
+    results: Vec<TaskResult>,
 }
 
 impl Task {
-    pub async fn try_from_scalping_task(task: ScalpingTask) -> Result<Self, ApiError> {
-        let db = get_db_manager();
-        let row = db
-            .query_one(
-                "SELECT * FROM fang_tasks WHERE metadata->>'eventId' = $1",
-                &[&task.event_id],
-            )
-            .await?;
+    pub async fn from_row(row: &Row) -> FieldResult<Self> {
+        let task = ScalpingTask::try_from(row)?;
+        let results = TaskResult::for_task(&task.key()).await?;
 
         // I don't bother inlining this
         let state: FangTaskState = row.get("state");
         let state: TaskState = state.into();
 
         Ok(Self {
+            id: row.get("id"),
+            results,
             event_id: task.event_id,
+            owner_id: task.owner_id,
             accounts: KideAccount::from_uuids(task.account_ids).await?,
             sale_start: task.sale_start,
             state,
             options: task.options,
         })
     }
+
+    fn key(&self) -> TaskKey {
+        TaskKey::new(self.event_id.clone(), self.owner_id)
+    }
+
+    // Looks up a task, `scope` limits it to tasks of a single owner
+    pub async fn find(id: Uuid, scope: Option<String>) -> FieldResult<Option<Self>> {
+        let db = get_db_manager();
+        let row = db
+            .query_opt(
+                "SELECT * FROM fang_tasks WHERE id = $1 AND metadata->>'type' = 'ScalpingTask' \
+                 AND ($2::text IS NULL OR metadata->>'ownerId' = $2)",
+                &[&id, &scope],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(Self::from_row(&row).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(
+    description = "The result of adding a task, the task is only scheduled if validation passed"
+)]
+struct AddTaskPayload {
+    task: Option<Task>,
+    validation: ValidationReport,
+}
+
+// ---- Event types ----
+
+// Read-only view of a sale as returned by the Kide API. Prices are in cents.
+struct Event {
+    sale: Sale,
+}
+
+#[graphql_object(context = Context, description = "An event and its variants")]
+impl Event {
+    fn id(&self) -> &str {
+        &self.sale.product.id
+    }
+
+    fn name(&self) -> &str {
+        &self.sale.product.name
+    }
+
+    fn company(&self) -> &str {
+        &self.sale.company.name
+    }
+
+    fn place(&self) -> &str {
+        &self.sale.product.place
+    }
+
+    fn date_actual_from(&self) -> DateTime<Utc> {
+        self.sale.product.date_actual_from
+    }
+
+    fn date_actual_until(&self) -> DateTime<Utc> {
+        self.sale.product.date_actual_until
+    }
+
+    fn date_sales_from(&self) -> DateTime<Utc> {
+        self.sale.product.date_sales_from
+    }
+
+    fn date_sales_until(&self) -> DateTime<Utc> {
+        self.sale.product.date_sales_until
+    }
+
+    fn sales_started(&self) -> bool {
+        self.sale.product.sales_started
+    }
+
+    fn sales_ended(&self) -> bool {
+        self.sale.product.sales_ended
+    }
+
+    fn sales_paused(&self) -> bool {
+        self.sale.product.sales_paused
+    }
+
+    fn is_haka_required(&self) -> bool {
+        self.sale.is_haka_required
+    }
+
+    fn categories(&self) -> Vec<EventCategory> {
+        self.sale
+            .categories
+            .iter()
+            .map(|category| EventCategory {
+                id: category.id.clone(),
+                name: category.name.clone(),
+            })
+            .collect()
+    }
+
+    // Null when there's no limit
+    fn max_total_reservations_per_checkout(&self) -> Option<i32> {
+        let limit = self.sale.product.max_total_reservations_per_checkout;
+        (limit > -1).then(|| clamp_i32(limit))
+    }
+
+    // Empty until the sale starts
+    fn variants(&self) -> Vec<EventVariant> {
+        self.sale.variants.iter().map(EventVariant::from).collect()
+    }
+
+    // How a task with these options would rank the variants, the first one not excluded is the
+    // one that gets reserved. With an account, variants it isn't eligible for are excluded.
+    async fn preview(
+        &self,
+        context: &Context,
+        options: Option<TaskOptionsInput>,
+        account: Option<Uuid>,
+    ) -> FieldResult<Vec<VariantPreview>> {
+        let mut task_options = TaskOptions::default();
+        if let Some(options) = options {
+            options.apply(&mut task_options);
+        }
+
+        let eligibility = match account {
+            Some(account) => {
+                context.ensure_accounts(&[account]).await?;
+                KideAccount::from_uuid(account)
+                    .await?
+                    .map(|account| account.eligibility)
+            }
+            None => None,
+        };
+
+        let strategy =
+            TicketPriorityStrategy::new(task_options).with_categories(&self.sale.categories);
+
+        let mut previews = Vec::new();
+        let ranking = strategy.rank(&self.sale.variants, eligibility.as_ref());
+        for (rank, ranked) in ranking.into_iter().enumerate() {
+            previews.push(VariantPreview {
+                rank: clamp_i32(rank as i64 + 1),
+                matches_name: strategy
+                    .matches_name(&ranked.variant.name)
+                    .map_err(ApiError::from)?,
+                matches_price: strategy.matches_price(ranked.variant.price_per_item),
+                variant: EventVariant::from(&ranked.variant),
+                breakdown: ranked.breakdown,
+            });
+        }
+
+        Ok(previews)
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A category of the whole event, usable in task category filters")]
+struct EventCategory {
+    id: String,
+    name: String,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A ticket variant of an event, prices are in cents")]
+struct EventVariant {
+    id: String,
+    name: String,
+    description: String,
+    inventory_id: String,
+    price_per_item: i32,
+    currency_code: String,
+    availability: i32,
+    max_per_user: i32,
+    min_reservable: i32,
+    max_reservable: i32,
+    product_type: i32,
+    date_sales_from: DateTime<Utc>,
+    requires_haka: bool,
+    requires_student_card: bool,
+    requires_membership: bool,
+    memberships: Vec<String>,
+}
+
+impl From<&Variant> for EventVariant {
+    fn from(variant: &Variant) -> Self {
+        Self {
+            id: variant.id.clone(),
+            name: variant.name.clone(),
+            description: variant.description.clone(),
+            inventory_id: variant.inventory_id.clone(),
+            price_per_item: clamp_i32(variant.price_per_item),
+            currency_code: variant.currency_code.clone(),
+            availability: clamp_i32(variant.availability),
+            max_per_user: clamp_i32(variant.product_variant_maximum_item_quantity_per_user),
+            min_reservable: clamp_i32(variant.product_variant_minimum_reservable_quantity),
+            max_reservable: clamp_i32(variant.product_variant_maximum_reservable_quantity),
+            product_type: clamp_i32(variant.product_type),
+            date_sales_from: variant.date_sales_from,
+            requires_haka: variant.is_product_variant_haka_authentication_required,
+            requires_student_card: variant.is_product_variant_student_card_required,
+            requires_membership: variant.is_product_variant_membership_required,
+            memberships: variant
+                .access_control_memberships
+                .iter()
+                .flatten()
+                .map(|membership| membership.name.clone())
+                .collect(),
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A variant as ranked by the ticket priority strategy")]
+struct VariantPreview {
+    rank: i32,
+    matches_name: bool,
+    matches_price: bool,
+    variant: EventVariant,
+    // Score and exclusion reason, same as stored with task results
+    breakdown: ScoreBreakdown,
+}
+
+// GraphQL ints are 32 bits, the Kide API uses 64 bit ones for everything
+fn clamp_i32(value: i64) -> i32 {
+    value.clamp(i32::MIN as i64, i32::MAX as i64) as i32
 }
 
 // ---- Query Root ----
@@ -107,76 +407,109 @@ impl Query {
         "1.0"
     }
 
+    async fn me(context: &Context) -> User {
+        context.user.clone()
+    }
+
+    async fn users(context: &Context) -> FieldResult<Vec<User>> {
+        if !context.user.is_admin() {
+            return Err(ApiError::Forbidden.into());
+        }
+
+        Ok(User::all().await?)
+    }
+
     // WARN: This feels pretty messy and not performant, but hey
     // Could be optimized by fetching the accounts only once and assigning here, but db operations
     // are cheap I guess...
-    async fn tasks(_context: &Context) -> FieldResult<Vec<Task>> {
+    async fn tasks(context: &Context) -> FieldResult<Vec<Task>> {
         let db = get_db_manager();
-        let rows = db.query("SELECT * FROM fang_tasks", &[]).await?;
+        // Reminders and other jobs share the table, only scalping tasks are listed
+        let rows = db
+            .query(
+                "SELECT * FROM fang_tasks WHERE metadata->>'type' = 'ScalpingTask' \
+                 AND ($1::text IS NULL OR metadata->>'ownerId' = $1)",
+                &[&context.task_scope()],
+            )
+            .await?;
 
         let mut tasks = Vec::new();
         for row in rows {
-            let task = ScalpingTask::try_from(&row)?;
-
-            // I don't bother inlining this
-            let state: FangTaskState = row.get("state");
-            let state: TaskState = state.into();
-
-            let accounts = KideAccount::from_uuids(task.account_ids).await?;
-
-            tasks.push(Task {
-                event_id: task.event_id,
-                accounts,
-                sale_start: task.sale_start,
-                state,
-                options: task.options,
-            });
+            tasks.push(Task::from_row(&row).await?);
         }
 
         Ok(tasks)
     }
 
-    async fn task(_context: &Context, event_id: String) -> FieldResult<Option<Task>> {
-        let db = get_db_manager();
-        let row = db
-            .query_opt(
-                "SELECT * FROM fang_tasks WHERE metadata->>'eventId' = $1",
-                &[&event_id],
-            )
-            .await?;
+    async fn task(context: &Context, id: Uuid) -> FieldResult<Option<Task>> {
+        Task::find(id, context.task_scope()).await
+    }
 
-        // check if we didn't get any rows
-        if row.is_none() {
-            return Ok(None);
+    // Looks up an event by its id or by its Kide url
+    async fn event(id: Option<String>, url: Option<String>) -> FieldResult<Event> {
+        let event_id = match (&id, &url) {
+            (Some(id), _) => id.as_str(),
+            (None, Some(url)) => {
+                event_id_from_url(url).ok_or_else(|| ApiError::EventNotFound(url.clone()))?
+            }
+            (None, None) => return Err(ApiError::EventIdRequired.into()),
+        };
+
+        match get_client().product(event_id.to_string()).await {
+            Ok(sale_client) => Ok(Event {
+                sale: sale_client.sale,
+            }),
+            Err(e) => {
+                log::debug!("Failed to fetch event {}: {}", event_id, e);
+                Err(ApiError::EventNotFound(event_id.to_string()).into())
+            }
         }
+    }
 
-        let row = row.unwrap();
+    async fn validate_task(
+        context: &Context,
+        input: AddTaskInput,
+    ) -> FieldResult<ValidationReport> {
+        context.ensure_accounts(&input.accounts).await?;
 
-        let task = ScalpingTask::try_from(&row)?;
-        let accounts = KideAccount::from_uuids(task.account_ids).await?;
+        let mut options = TaskOptions::default();
+        if let Some(options_input) = input.options {
+            options_input.apply(&mut options);
+        }
 
-        // I don't bother inlining this
-        let state: FangTaskState = row.get("state");
-        let state: TaskState = state.into();
+        let preflight =
+            validate_task(get_client(), &input.event_id, &input.accounts, &options).await?;
 
-        Ok(Some(Task {
-            event_id: task.event_id,
-            accounts,
-            sale_start: task.sale_start,
-            state,
-            options: task.options,
-        }))
+        Ok(preflight.report)
     }
 
-    async fn kide_accounts(_context: &Context) -> FieldResult<Vec<KideAccount>> {
-        let accounts = KideAccount::all().await.unwrap();
+    async fn kide_accounts(context: &Context) -> FieldResult<Vec<KideAccount>> {
+        let accounts = KideAccount::all(context.user.scope()).await?;
         Ok(accounts)
     }
 
-    async fn kide_account(_context: &Context, uuid: Uuid) -> FieldResult<Option<KideAccount>> {
-        // TODO: Maybe separate KideAccount::from_uuid into a fetch_kide_account and fetch_kide_accounts
-        let accounts = KideAccount::from_uuids(vec![uuid]).await.unwrap();
-        Ok(accounts.into_iter().next())
+    async fn kide_account(context: &Context, uuid: Uuid) -> FieldResult<Option<KideAccount>> {
+        let account = KideAccount::from_uuid(uuid)
+            .await?
+            .filter(|account| context.user.can_access(account.owner_uuid));
+        Ok(account)
+    }
+
+    async fn webhooks(context: &Context) -> FieldResult<Vec<Webhook>> {
+        let webhooks = Webhook::all(context.user.scope()).await?;
+        Ok(webhooks)
+    }
+
+    // Latest delivery attempts of a webhook, newest first
+    async fn webhook_deliveries(
+        context: &Context,
+        uuid: Uuid,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<WebhookDelivery>> {
+        context.ensure_webhook(uuid).await?;
+
+        let limit = limit.unwrap_or(50).clamp(1, 500) as i64;
+        Ok(WebhookDelivery::for_webhook(uuid, limit).await?)
     }
 }
 
@@ -185,14 +518,39 @@ impl Query {
 #[derive(GraphQLInputObject)]
 struct AddKideAccountInput {
     name: String,
-    token: String,
+    token: Secret,
+    eligibility: Option<EligibilityInput>,
 }
 
 #[derive(GraphQLInputObject)]
 struct UpdateKideAccountInput {
     id: Uuid,
     name: Option<String>,
-    token: Option<String>,
+    token: Option<Secret>,
+    eligibility: Option<EligibilityInput>,
+}
+
+// Replaces the whole profile, accounts without one can't buy variants with requirements
+#[derive(GraphQLInputObject)]
+struct EligibilityInput {
+    has_student_card: bool,
+    has_haka: bool,
+    membership_ids: Vec<String>,
+    #[graphql(
+        description = "Most the account may spend on everything it reserves in a task, in cents"
+    )]
+    budget: Option<i32>,
+}
+
+impl From<EligibilityInput> for Eligibility {
+    fn from(input: EligibilityInput) -> Self {
+        Self {
+            has_student_card: input.has_student_card,
+            has_haka: input.has_haka,
+            membership_ids: input.membership_ids,
+            budget: input.budget,
+        }
+    }
 }
 
 #[derive(GraphQLInputObject)]
@@ -209,7 +567,7 @@ struct AddTaskInput {
 
 #[derive(GraphQLInputObject)]
 struct UpdateTaskInput {
-    event_id: String,
+    id: Uuid,
     accounts: Option<Vec<Uuid>>,
     options: Option<TaskOptionsInput>,
 }
@@ -217,15 +575,110 @@ struct UpdateTaskInput {
 // Used for both AddTaskInput and UpdateTaskInput
 #[derive(GraphQLInputObject)]
 struct TaskOptionsInput {
+    // In cents, like the price range
     target_price: Option<i32>,
-    ignore_membership: Option<bool>,
+    // In cents
+    min_price: Option<i32>,
+    max_price: Option<i32>,
+    // Replace the current filters when given
+    #[graphql(
+        description = "Categories, nothing is reserved unless the event is in one. Kide doesn't \
+                       list categories per variant."
+    )]
+    include_categories: Option<Vec<String>>,
+    #[graphql(
+        description = "Categories, nothing is reserved if the event is in one. Kide doesn't list \
+                       categories per variant."
+    )]
+    exclude_categories: Option<Vec<String>>,
+    include_product_types: Option<Vec<i32>>,
+    exclude_product_types: Option<Vec<i32>>,
+    #[graphql(
+        description = "All-or-nothing ticket count, not for restocks or variants opening later"
+    )]
+    group_size: Option<i32>,
+    group_deadline_seconds: Option<i32>,
+    restock_watch_seconds: Option<i32>,
+    restock_poll_seconds: Option<i32>,
     target_name: Option<String>,
     use_regex: Option<bool>,
 }
 
+impl TaskOptionsInput {
+    fn apply(self, options: &mut TaskOptions) {
+        if let Some(price) = self.target_price {
+            options.target_price = Some(price);
+        }
+        if let Some(min_price) = self.min_price {
+            options.min_price = Some(min_price);
+        }
+        if let Some(max_price) = self.max_price {
+            options.max_price = Some(max_price);
+        }
+        if let Some(categories) = self.include_categories {
+            options.include_categories = categories;
+        }
+        if let Some(categories) = self.exclude_categories {
+            options.exclude_categories = categories;
+        }
+        if let Some(product_types) = self.include_product_types {
+            options.include_product_types = product_types;
+        }
+        if let Some(product_types) = self.exclude_product_types {
+            options.exclude_product_types = product_types;
+        }
+        if let Some(group_size) = self.group_size {
+            options.group_size = Some(group_size);
+        }
+        if let Some(deadline) = self.group_deadline_seconds {
+            options.group_deadline_seconds = Some(deadline);
+        }
+        if let Some(watch) = self.restock_watch_seconds {
+            options.restock_watch_seconds = Some(watch);
+        }
+        if let Some(interval) = self.restock_poll_seconds {
+            options.restock_poll_seconds = Some(interval);
+        }
+        if let Some(name) = self.target_name {
+            options.target_name = Some(name);
+        }
+        if let Some(regex) = self.use_regex {
+            options.use_regex = regex;
+        }
+    }
+}
+
 #[derive(GraphQLInputObject)]
 struct DeleteTaskInput {
-    event_id: String,
+    id: Uuid,
+}
+
+#[derive(GraphQLInputObject)]
+struct AddWebhookInput {
+    url: String,
+    // Payloads are signed with it, see the X-Crystal-Signature header
+    secret: Secret,
+    // The task lifecycle if not given
+    events: Option<Vec<TaskEventKind>>,
+    // Raw if not given
+    format: Option<WebhookFormat>,
+    // Sent the events of every task instead of only your own, admins only
+    all_tasks: Option<bool>,
+}
+
+#[derive(GraphQLInputObject)]
+struct UpdateWebhookInput {
+    id: Uuid,
+    url: Option<String>,
+    // Replaces the current filter when given
+    events: Option<Vec<TaskEventKind>>,
+    format: Option<WebhookFormat>,
+    enabled: Option<bool>,
+}
+
+#[derive(GraphQLInputObject)]
+struct DeleteWebhookInput {
+    id: Uuid,
 }
 
 // ---- Mutation Root ----
@@ -235,23 +688,37 @@ pub struct Mutation;
 #[juniper::graphql_object(context = Context)]
 impl Mutation {
     async fn add_kide_account(
-        _context: &Context,
+        context: &Context,
         input: AddKideAccountInput,
     ) -> FieldResult<KideAccount> {
-        // Implement the logic to add a new KideAccount using the input object
-        Ok(KideAccount::create(input.name, input.token).await?)
+        let eligibility = input.eligibility.map(Eligibility::from).unwrap_or_default();
+
+        Ok(KideAccount::create(
+            input.name,
+            input.token,
+            Some(context.user.uuid),
+            eligibility,
+        )
+        .await?)
     }
 
     async fn update_kide_account(
-        _context: &Context,
+        context: &Context,
         input: UpdateKideAccountInput,
     ) -> FieldResult<Option<KideAccount>> {
+        context.ensure_accounts(&[input.id]).await?;
+
         let mut account = KideAccount::from_uuid(input.id)
             .await?
             .ok_or_else(|| ApiError::KideAccountNotFound(input.id))?;
 
         input.name.map(|name| account.name = name);
-        input.token.map(|token| account.token = token);
+        if let Some(token) = input.token {
+            account.set_token(&token)?;
+        }
+        if let Some(eligibility) = input.eligibility {
+            account.eligibility = eligibility.into();
+        }
 
         account.save().await?;
 
@@ -259,131 +726,305 @@ impl Mutation {
     }
 
     async fn delete_kide_account(
-        _context: &Context,
+        context: &Context,
         input: DeleteKideAccountInput,
     ) -> FieldResult<Uuid> {
+        context.ensure_accounts(&[input.id]).await?;
+
         KideAccount::delete(input.id).await?;
         Ok(input.id)
     }
 
-    async fn add_task(context: &Context, input: AddTaskInput) -> FieldResult<Task> {
+    async fn add_task(context: &Context, input: AddTaskInput) -> FieldResult<AddTaskPayload> {
+        context.ensure_accounts(&input.accounts).await?;
+
         // Fetch event details
-        //
-        // NOTE: Could be better to have a permanent client in the context, but load will be so
-        // low that it doesn't matter
-        let client = Client::new();
-        let sale_client = client.product(input.event_id.clone()).await.unwrap();
+        let client = get_client();
 
         let mut options = TaskOptions::default();
 
         // Set options if they were provided
-        input.options.map(|options_input| {
-            options_input
-                .target_price
-                .map(|price| options.target_price = Some(price));
-            options_input
-                .target_name
-                .map(|name| options.target_name = Some(name));
-            options_input
-                .use_regex
-                .map(|regex| options.use_regex = regex);
-            options_input
-                .ignore_membership
-                .map(|ignore| options.ignore_membership = ignore);
-        });
+        if let Some(options_input) = input.options {
+            options_input.apply(&mut options);
+        }
+
+        let preflight = validate_task(client, &input.event_id, &input.accounts, &options).await?;
+
+        if !preflight.report.is_ok() {
+            return Ok(AddTaskPayload {
+                task: None,
+                validation: preflight.report,
+            });
+        }
+
+        let sale = preflight
+            .sale
+            .ok_or_else(|| ApiError::EventNotFound(input.event_id.clone()))?;
+
+        // Events and results of a task are told apart by event and owner, so an owner only gets
+        // one task per event
+        if ScalpingTask::exists(&input.event_id, Some(context.user.uuid)).await? {
+            return Err(ApiError::TaskExists(input.event_id).into());
+        }
 
         let task = ScalpingTask::new(
             input.event_id,
             input.accounts,
-            sale_client.sale.product.date_sales_from,
+            sale.product.date_sales_from,
             options,
+            Some(context.user.uuid),
         );
 
         // Lock the queue for writing
         let mut queue = context.queue.write().await;
 
         // Queue new task for workers
-        queue
-            .schedule_task(&task as &dyn AsyncRunnable)
-            .await
-            .unwrap();
-
-        Ok(Task::try_from_scalping_task(task).await?)
+        let scheduled = queue.schedule_task(&task as &dyn AsyncRunnable).await?;
+        TaskEvent::emit(
+            &task.key(),
+            TaskEventKind::Scheduled,
+            "Task scheduled",
+            None,
+        )
+        .await;
+
+        Ok(AddTaskPayload {
+            task: Task::find(scheduled.id, None).await?,
+            validation: preflight.report,
+        })
     }
 
-    async fn update_task(_context: &Context, input: UpdateTaskInput) -> FieldResult<Option<Task>> {
+    async fn update_task(context: &Context, input: UpdateTaskInput) -> FieldResult<Option<Task>> {
         // TODO: This much logic shouldn't be here
         let db = get_db_manager();
         let row = db
             .query_opt(
-                "SELECT * FROM fang_tasks WHERE metadata->>'eventId' = $1",
-                &[&input.event_id],
+                "SELECT * FROM fang_tasks WHERE id = $1 AND metadata->>'type' = 'ScalpingTask' \
+                 AND ($2::text IS NULL OR metadata->>'ownerId' = $2)",
+                &[&input.id, &context.task_scope()],
             )
             .await?;
 
         // check if we didn't get any rows
-        if row.is_none() {
-            return Ok(None);
-        }
-
-        let row = row.unwrap();
-
-        // I don't bother inlining this
-        let state: FangTaskState = row.get("state");
-        let state: TaskState = state.into();
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
 
         let mut task = ScalpingTask::try_from(&row)?;
-        input.accounts.map(|accounts| task.account_ids = accounts);
+        if let Some(accounts) = input.accounts {
+            context.ensure_accounts(&accounts).await?;
+            task.account_ids = accounts;
+        }
 
         // Set options if they were provided
-        input.options.map(|options_input| {
-            options_input
-                .target_price
-                .map(|price| task.options.target_price = Some(price));
-            options_input
-                .target_name
-                .map(|name| task.options.target_name = Some(name));
-            options_input
-                .use_regex
-                .map(|regex| task.options.use_regex = regex);
-            options_input
-                .ignore_membership
-                .map(|ignore| task.options.ignore_membership = ignore);
-        });
+        if let Some(options_input) = input.options {
+            options_input.apply(&mut task.options);
+        }
 
         let metadata = serde_json::to_value(&task as &dyn AsyncRunnable)?;
 
-        let _ = db
-            .execute(
-                "UPDATE fang_tasks SET metadata = $1 WHERE metadata->>'eventId' = $2",
-                &[&metadata, &task.event_id],
-            )
-            .await;
-
-        let accounts = KideAccount::from_uuids(task.account_ids).await?;
+        db.execute(
+            "UPDATE fang_tasks SET metadata = $1 WHERE id = $2",
+            &[&metadata, &input.id],
+        )
+        .await?;
 
-        Ok(Some(Task {
-            event_id: task.event_id,
-            accounts,
-            sale_start: task.sale_start,
-            state,
-            options: task.options,
-        }))
+        Task::find(input.id, None).await
     }
 
-    async fn delete_task(_context: &Context, input: DeleteTaskInput) -> FieldResult<String> {
+    async fn delete_task(context: &Context, input: DeleteTaskInput) -> FieldResult<Uuid> {
         let db = get_db_manager();
-        let _ = db
-            .execute(
-                "DELETE FROM fang_tasks WHERE metadata->>'eventId' = $1",
-                &[&input.event_id],
+        let deleted = db
+            .query_opt(
+                "DELETE FROM fang_tasks WHERE id = $1 AND metadata->>'type' = 'ScalpingTask' \
+                 AND ($2::text IS NULL OR metadata->>'ownerId' = $2) RETURNING metadata",
+                &[&input.id, &context.task_scope()],
             )
             .await?;
 
-        Ok(input.event_id)
+        let task = match deleted {
+            Some(row) => ScalpingTask::try_from(&row)?,
+            None => return Err(ApiError::TaskNotFound(input.id).into()),
+        };
+
+        // Rounds scheduled for variants opening later and reminders of its holds belong to the
+        // task too
+        db.execute(
+            DELETE_PENDING_RUNS,
+            &[
+                &task.event_id,
+                &task.owner_id.map(|owner_id| owner_id.to_string()),
+            ],
+        )
+        .await?;
+
+        Ok(input.id)
+    }
+
+    // Where task outcomes and token expiry warnings are mailed, none stops the emails
+    async fn set_email(context: &Context, email: Option<String>) -> FieldResult<User> {
+        if let Some(email) = &email {
+            email
+                .parse::<lettre::Address>()
+                .map_err(|e| ApiError::InvalidEmail(e.to_string()))?;
+        }
+
+        let mut user = context.user.clone();
+        user.set_email(email).await?;
+
+        Ok(user)
+    }
+
+    async fn add_webhook(context: &Context, input: AddWebhookInput) -> FieldResult<Webhook> {
+        reqwest::Url::parse(&input.url).map_err(|e| ApiError::InvalidWebhookUrl(e.to_string()))?;
+
+        let owner = match input.all_tasks {
+            Some(true) if context.user.is_admin() => None,
+            Some(true) => return Err(ApiError::Forbidden.into()),
+            _ => Some(context.user.uuid),
+        };
+
+        Ok(Webhook::create(
+            owner,
+            input.url,
+            &input.secret,
+            &input.events.unwrap_or_default(),
+            input.format.unwrap_or(WebhookFormat::Raw),
+        )
+        .await?)
+    }
+
+    async fn update_webhook(context: &Context, input: UpdateWebhookInput) -> FieldResult<Webhook> {
+        let mut webhook = context.ensure_webhook(input.id).await?;
+
+        if let Some(url) = input.url {
+            reqwest::Url::parse(&url).map_err(|e| ApiError::InvalidWebhookUrl(e.to_string()))?;
+            webhook.url = url;
+        }
+        if let Some(events) = input.events {
+            webhook.events = events;
+        }
+        if let Some(format) = input.format {
+            webhook.format = format;
+        }
+        if let Some(enabled) = input.enabled {
+            webhook.enabled = enabled;
+        }
+
+        webhook.save().await?;
+
+        Ok(webhook)
+    }
+
+    async fn delete_webhook(context: &Context, input: DeleteWebhookInput) -> FieldResult<Uuid> {
+        context.ensure_webhook(input.id).await?;
+
+        Webhook::delete(input.id).await?;
+        Ok(input.id)
+    }
+
+    // Queues a ping to the webhook, its delivery shows up in the webhook's deliveries
+    async fn test_webhook(context: &Context, id: Uuid) -> FieldResult<Uuid> {
+        context.ensure_webhook(id).await?;
+
+        let task = WebhookDeliveryTask::new(
+            id,
+            WebhookPayload {
+                kind: "ping".to_string(),
+                message: "Webhook test".to_string(),
+                created_at: Utc::now(),
+                ..Default::default()
+            },
+        );
+
+        let mut queue = context.queue.write().await;
+        queue.insert_task(&task as &dyn AsyncRunnable).await?;
+
+        Ok(id)
+    }
+}
+
+// ---- Subscription Root ----
+
+type TaskEventStream = Pin<Box<dyn Stream<Item = FieldResult<TaskEvent>> + Send>>;
+type TaskStream = Pin<Box<dyn Stream<Item = FieldResult<Task>> + Send>>;
+
+pub struct Subscription;
+
+#[graphql_subscription(context = Context)]
+impl Subscription {
+    // Every event of the task, starting with the ones recorded before subscribing
+    async fn task_events(context: &Context, id: Uuid) -> FieldResult<TaskEventStream> {
+        let task = Task::find(id, context.task_scope())
+            .await?
+            .ok_or(ApiError::TaskNotFound(id))?;
+
+        let stream = TaskEvent::watch(task.key(), 0).map(|event| event.map_err(FieldError::from));
+        Ok(Box::pin(stream))
+    }
+
+    // The task, every time something happens to it
+    async fn task_updated(context: &Context, id: Uuid) -> FieldResult<TaskStream> {
+        let scope = context.task_scope();
+        let task = Task::find(id, scope.clone())
+            .await?
+            .ok_or(ApiError::TaskNotFound(id))?;
+
+        let after = TaskEvent::latest_id(&task.key()).await?;
+        let stream = TaskEvent::watch(task.key(), after)
+            .then(move |event| task_snapshot(id, scope.clone(), event));
+        Ok(Box::pin(stream))
+    }
+}
+
+async fn task_snapshot(
+    id: Uuid,
+    scope: Option<String>,
+    event: Result<TaskEvent, DBError>,
+) -> FieldResult<Task> {
+    event?;
+
+    match Task::find(id, scope).await? {
+        Some(task) => Ok(task),
+        None => Err(ApiError::TaskNotFound(id).into()),
     }
 }
 
 // ---- Schema ----
 
-pub type Schema = juniper::RootNode<'static, Query, Mutation, EmptySubscription<Context>>;
+pub type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reminder::HoldReminderTask;
+    use crate::scalp::RoundState;
+    use crate::task::VariantRoundTask;
+
+    fn metadata(task: &dyn AsyncRunnable) -> serde_json::Value {
+        serde_json::to_value(task).unwrap()
+    }
+
+    #[test]
+    fn deletes_pending_rounds_and_hold_reminders() {
+        let owner_id = Uuid::new_v4();
+        let round = metadata(&VariantRoundTask::new(
+            TaskKey::new("event", Some(owner_id)),
+            vec![],
+            TaskOptions::default(),
+            Utc::now(),
+            RoundState::default(),
+        ));
+        let reminder = metadata(&HoldReminderTask::new(1, Utc::now(), Some(owner_id)));
+
+        // The keys the statement filters on have to be the ones the runs are stored with
+        for metadata in [&round, &reminder] {
+            let kind = metadata["type"].as_str().unwrap();
+            assert!(DELETE_PENDING_RUNS.contains(&format!("'{}'", kind)));
+            assert_eq!(metadata["ownerId"], owner_id.to_string());
+        }
+        assert_eq!(round["eventId"], "event");
+        assert_eq!(reminder["resultId"], 1);
+    }
+}
@@ -0,0 +1,335 @@
+use chrono::Utc;
+use fang::FangError;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use crate::account::KideAccount;
+use crate::api::Variant;
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::request::{BatchReservation, Client, VariantReservation};
+use crate::result::TaskResult;
+use crate::sale::{reserved_until, SaleClient};
+use crate::scalp::remaining_budget;
+use crate::secret::Secret;
+use crate::strategy::{All, Count, Quantity, TicketPriorityStrategy};
+use crate::task::TaskKey;
+
+pub const DEFAULT_GROUP_DEADLINE_SECONDS: i32 = 60;
+const GROUP_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+// A reservation made for the group, kept so it can be released again
+struct Hold {
+    result_id: Option<i64>,
+    account: KideAccount,
+    token: Secret,
+    reservation: VariantReservation,
+    // How much of the variant the account held before reserving it for the group, None if the
+    // basket couldn't be read
+    held_before: Option<i64>,
+}
+
+// Reserves `size` tickets across the accounts, every account reserving as many of its chosen
+// variant as it is allowed to. Accounts that fail are retried until the deadline. If the group
+// isn't complete by then, everything reserved for it is released.
+pub async fn book_group(
+    task: &TaskKey,
+    client: &Client,
+    mut sale_client: SaleClient,
+    accounts: Vec<KideAccount>,
+    priority_strategy: &TicketPriorityStrategy,
+    size: i64,
+    deadline: Duration,
+) -> Result<(), FangError> {
+    let deadline = Instant::now() + deadline;
+
+    // Tokens are only ever decrypted here, right before they are needed
+    let mut pending = Vec::new();
+    for account in accounts {
+        let token = account.decrypt_token()?;
+        pending.push((account, token));
+    }
+
+    let mut holds: Vec<Hold> = Vec::new();
+    let mut total = 0;
+
+    while total < size && !pending.is_empty() && Instant::now() < deadline {
+        let mut still_pending = Vec::new();
+
+        for (account, token) in pending {
+            if total >= size {
+                still_pending.push((account, token));
+                continue;
+            }
+
+            // Variants opening later than the sale aren't waited for, the group has a deadline
+            let remaining = size - total;
+            let candidates: Vec<Variant> = sale_client
+                .sale
+                .open_variants(Utc::now())
+                .into_iter()
+                .filter(|variant| group_quantity(variant, remaining).is_some())
+                .collect();
+            let (chosen, ranking) =
+                priority_strategy.choose_explained(&candidates, Some(&account.eligibility));
+            let result_id =
+                TaskResult::record(task, Some(account.uuid), chosen.as_ref(), &ranking).await;
+
+            let variant = match chosen {
+                Some(variant) => variant,
+                None => {
+                    still_pending.push((account, token));
+                    continue;
+                }
+            };
+
+            let quantity = group_quantity(&variant, remaining).unwrap_or_default();
+
+            let budget = remaining_budget(task, &account).await?;
+            let before = sale_client.snapshot(&token).await;
+            let result = sale_client
+                .reserve(&variant, &token, &Count { count: quantity }, budget)
+                .await;
+
+            // Only what made it into the basket counts towards the group
+            let (granted, expires_at) = match (result, &before) {
+                (Ok(response), Some(before)) => {
+                    let reservation = variant.to_reservation(&Count { count: quantity });
+                    match sale_client
+                        .verify(&token, before, &[reservation], response.as_ref())
+                        .await
+                    {
+                        Ok((reconciled, _)) => (
+                            reconciled.iter().map(|r| r.granted).sum(),
+                            reconciled.iter().filter_map(|r| r.expires_at).min(),
+                        ),
+                        Err(e) => {
+                            log::warn!("Could not verify the basket of {}: {}", account.name, e);
+                            let expires_at = reserved_until(
+                                response.as_ref(),
+                                &variant.inventory_id,
+                                Utc::now(),
+                            );
+                            (quantity, expires_at)
+                        }
+                    }
+                }
+                (Ok(response), None) => (
+                    quantity,
+                    reserved_until(response.as_ref(), &variant.inventory_id, Utc::now()),
+                ),
+                (Err(e), _) => {
+                    TaskResult::reconcile(result_id, quantity, Some(0), None).await;
+                    TaskEvent::emit(
+                        task,
+                        TaskEventKind::ReservationFailed,
+                        format!("Reservation for {} failed: {}", account.name, e),
+                        Some(account.uuid),
+                    )
+                    .await;
+
+                    still_pending.push((account, token));
+                    continue;
+                }
+            };
+            TaskResult::reconcile(result_id, quantity, Some(granted), expires_at).await;
+
+            match granted {
+                0 => {
+                    TaskEvent::emit(
+                        task,
+                        TaskEventKind::ReservationFailed,
+                        format!(
+                            "{} for {} was not in the basket",
+                            variant.name, account.name
+                        ),
+                        Some(account.uuid),
+                    )
+                    .await;
+
+                    still_pending.push((account, token));
+                }
+                granted => {
+                    total += granted;
+                    TaskEvent::emit(
+                        task,
+                        TaskEventKind::ReservationSucceeded,
+                        format!(
+                            "Reserved {} of {} for {}, {} of {} for the group",
+                            granted, variant.name, account.name, total, size
+                        ),
+                        Some(account.uuid),
+                    )
+                    .await;
+
+                    holds.push(Hold {
+                        result_id,
+                        held_before: before
+                            .as_ref()
+                            .map(|before| before.held(&variant.inventory_id)),
+                        reservation: variant.to_reservation(&Count { count: granted }),
+                        account,
+                        token,
+                    });
+                }
+            }
+        }
+
+        pending = still_pending;
+
+        if total < size && !pending.is_empty() {
+            tokio::time::sleep(GROUP_RETRY_INTERVAL).await;
+
+            match client.product(task.event_id.clone()).await {
+                Ok(latest) => sale_client = latest,
+                Err(e) => log::warn!("Failed to refresh event {}: {}", task.event_id, e),
+            }
+        }
+    }
+
+    if total >= size {
+        TaskEvent::emit(
+            task,
+            TaskEventKind::GroupCompleted,
+            format!("Reserved {} tickets across {} accounts", total, holds.len()),
+            None,
+        )
+        .await;
+
+        return Ok(());
+    }
+
+    release(task, client, &holds).await;
+
+    TaskEvent::emit(
+        task,
+        TaskEventKind::GroupReleased,
+        format!(
+            "Only {} of {} tickets could be reserved, released everything",
+            total, size
+        ),
+        None,
+    )
+    .await;
+
+    Ok(())
+}
+
+// How many of the variant to reserve for the group, never more than the group still needs or the
+// account may reserve. None if the variant's minimum is more than that, reserving it would
+// overshoot the group.
+fn group_quantity(variant: &Variant, remaining: i64) -> Option<i64> {
+    let quantity = cmp::min(remaining, All.quantity(variant));
+    let minimum = cmp::max(variant.product_variant_minimum_reservable_quantity, 1);
+
+    if quantity < minimum {
+        None
+    } else {
+        Some(quantity)
+    }
+}
+
+// How much of a hold to cancel. Only what the basket gained since before the group's reservation
+// is the group's, holds the account had before are left alone. Without both baskets it falls back
+// to what the group was granted.
+fn release_quantity(granted: i64, held_before: Option<i64>, held_now: Option<i64>) -> i64 {
+    match (held_before, held_now) {
+        (Some(before), Some(now)) => (now - before).clamp(0, granted),
+        _ => granted,
+    }
+}
+
+async fn release(task: &TaskKey, client: &Client, holds: &[Hold]) {
+    for hold in holds {
+        let held_now = match client.basket(&hold.token).await {
+            Ok(basket) => Some(basket.held(&hold.reservation.inventory_id)),
+            Err(e) => {
+                log::warn!("Could not read the basket of {}: {}", hold.account.name, e);
+                None
+            }
+        };
+        let quantity = release_quantity(hold.reservation.quantity, hold.held_before, held_now);
+        if quantity == 0 {
+            TaskResult::release(hold.result_id).await;
+            continue;
+        }
+
+        let batch = BatchReservation::cancel(&VariantReservation {
+            inventory_id: hold.reservation.inventory_id.clone(),
+            quantity,
+        });
+
+        match client.reserve(&batch, &hold.token).await {
+            Ok(_) => {
+                log::debug!("Released reservation of {}", hold.account.name);
+                TaskResult::release(hold.result_id).await;
+            }
+            Err(e) => {
+                TaskEvent::emit(
+                    task,
+                    TaskEventKind::ReservationFailed,
+                    format!(
+                        "Could not release the reservation of {}: {}",
+                        hold.account.name, e
+                    ),
+                    Some(hold.account.uuid),
+                )
+                .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(minimum: i64, availability: i64) -> Variant {
+        Variant {
+            availability,
+            product_variant_minimum_reservable_quantity: minimum,
+            product_variant_maximum_reservable_quantity: 10,
+            product_variant_maximum_item_quantity_per_user: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reserves_no_more_than_the_group_needs() {
+        assert_eq!(group_quantity(&variant(1, 100), 3), Some(3));
+    }
+
+    #[test]
+    fn reserves_no_more_than_the_account_may() {
+        assert_eq!(group_quantity(&variant(1, 100), 20), Some(10));
+        assert_eq!(group_quantity(&variant(1, 4), 20), Some(4));
+    }
+
+    #[test]
+    fn skips_variants_whose_minimum_overshoots_the_group() {
+        assert_eq!(group_quantity(&variant(4, 100), 3), None);
+        assert_eq!(group_quantity(&variant(3, 100), 3), Some(3));
+    }
+
+    #[test]
+    fn skips_variants_without_enough_left_for_the_minimum() {
+        assert_eq!(group_quantity(&variant(4, 2), 10), None);
+    }
+
+    #[test]
+    fn releases_only_what_the_group_added() {
+        assert_eq!(release_quantity(2, Some(1), Some(3)), 2);
+        assert_eq!(release_quantity(2, Some(3), Some(4)), 1);
+        assert_eq!(release_quantity(2, Some(3), Some(3)), 0);
+    }
+
+    #[test]
+    fn releases_no_more_than_was_granted() {
+        assert_eq!(release_quantity(2, Some(0), Some(5)), 2);
+    }
+
+    #[test]
+    fn releases_what_was_granted_without_both_baskets() {
+        assert_eq!(release_quantity(2, None, Some(5)), 2);
+        assert_eq!(release_quantity(2, Some(1), None), 2);
+    }
+}
@@ -1,11 +1,19 @@
-use crystal::task::ScalpingTask;
+use crystal::reminder::HoldReminderTask;
+use crystal::task::{ScalpingTask, TaskKey, VariantRoundTask};
+use crystal::email::{initialize_mailer, TaskOutcomeEmailTask, TokenExpiryCheckTask};
+use crystal::webhook::WebhookDeliveryTask;
 use dotenvy::dotenv;
+use fang::asynk::async_queue::AsyncQueueable;
 use fang::asynk::AsyncRunnable;
 use std::env;
 
+use crystal::account::KideAccount;
+use crystal::crypto::initialize_keyring;
 use crystal::db::do_migrations;
-use crystal::queue::connect_to_queue;
-use crystal::worker::create_worker_pool;
+use crystal::queue::{connect_to_queue, initialize_dispatch_queue};
+use crystal::notify::initialize_listener;
+use crystal::ratelimit::spawn_metrics_reporter;
+use crystal::worker::{create_worker_pool, spawn_task_waker};
 use crystal::db::initialize_db_manager;
 
 #[tokio::main]
@@ -25,7 +33,19 @@ async fn main() {
         vec![],
         chrono::Utc::now(),
         Default::default(),
+        None,
     ));
+    let _: Box<dyn AsyncRunnable> = Box::new(HoldReminderTask::new(0, chrono::Utc::now(), None));
+    let _: Box<dyn AsyncRunnable> = Box::new(VariantRoundTask::new(
+        TaskKey::new("", None),
+        vec![],
+        Default::default(),
+        chrono::Utc::now(),
+        Default::default(),
+    ));
+    let _: Box<dyn AsyncRunnable> =
+        Box::new(WebhookDeliveryTask::new(uuid::Uuid::nil(), Default::default()));
+    let _: Box<dyn AsyncRunnable> = Box::new(TaskOutcomeEmailTask::default());
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
@@ -38,7 +58,35 @@ async fn main() {
     log::info!("Queue connected...");
 
     log::info!("Initializing db manager...");
-    initialize_db_manager(database_url).await;
+    initialize_db_manager(database_url.clone()).await;
+
+    log::info!("Loading token encryption keys...");
+    initialize_keyring();
+
+    let encrypted = KideAccount::encrypt_plaintext_tokens().await.unwrap();
+    if encrypted > 0 {
+        log::info!("Encrypted {} plaintext tokens", encrypted);
+    }
+
+    log::info!("Starting notification listener...");
+    initialize_listener(database_url);
+    spawn_task_waker(queue.clone());
+
+    // Events recorded by the tasks are delivered to webhooks and by email by the pool as well
+    initialize_dispatch_queue(queue.clone());
+
+    log::info!("Setting up email...");
+    initialize_mailer();
+
+    // Unique, so restarting the workers doesn't schedule it again
+    let mut check_queue = queue.clone();
+    check_queue
+        .schedule_task(&TokenExpiryCheckTask::default() as &dyn AsyncRunnable)
+        .await
+        .unwrap();
+
+    // Requests to Kide are throttled per worker, this logs how much
+    spawn_metrics_reporter();
 
     let mut pool = create_worker_pool(queue);
 
@@ -3,9 +3,24 @@ pub mod api;
 pub mod request;
 pub mod strategy;
 pub mod task;
+pub mod user;
 pub mod scalp;
+pub mod poller;
+pub mod ratelimit;
+pub mod group;
 pub mod db;
+pub mod event;
+pub mod result;
+pub mod reminder;
+pub mod webhook;
+pub mod chat;
+pub mod email;
 pub mod queue;
+pub mod notify;
 pub mod worker;
 pub mod account;
+pub mod auth;
+pub mod crypto;
+pub mod secret;
 pub mod graphql;
+pub mod validation;
@@ -1,72 +1,179 @@
-use sublime_fuzzy::best_match;
-
-const WORDS: [&str; 17] = [
-    "4 hengen A-hytti",
-    "3 hengen A-hytti",
-    "3 hengen A-hytti – handicap",
-    "2 hengen A-hytti",
-    "2 hengen A-hytti – handicap",
-    "1 hengen A-hytti",
-    "4 hengen Promenade-hytti",
-    "4 hengen Promenade-hytti – allergia",
-    "3 hengen Promenade-hytti – handicap",
-    "2 hengen Promenade-hytti",
-    "2 hengen Promenade-hytti – allergia",
-    "4 hengen B-hytti",
-    "2 hengen B-hytti",
-    "2 hengen B-hytti – allergia",
-    "4 hengen C-hytti",
-    "3 hengen C-hytti",
-    "2 hengen C-hytti",
-];
-
-const NEGATIVE_WORDS: [&str; 3] = [
-    "allergia",
-    "handicap",
-    "inva",
-];
-
-const POSITIVE_WORDS: [&str; 4] = [
-    "4 hengen",
-    "Promenade",
-    "A-hytti",
-    "helga",
-];
-
-fn score_word(word: &str) -> isize {
-    let mut positive_score = 0;
-    let mut negative_score = 0;
-
-    for positive_word in POSITIVE_WORDS.iter() {
-        let score = match best_match(positive_word, word) {
-            Some(m) => m.score(),
-            None => 0,
-        };
+// Strategy playground: shows how a task would rank the variants of an event, without reserving
+// anything or touching the database
+use crystal::account::Eligibility;
+use crystal::request::{event_id_from_url, get_client};
+use crystal::sale::Sale;
+use crystal::strategy::TicketPriorityStrategy;
+use crystal::task::TaskOptions;
 
-        positive_score += score;
-    }
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
 
-    for negative_word in NEGATIVE_WORDS.iter() {
-        let score = match best_match(negative_word, word) {
-            Some(m) => m.score(),
-            None => 0,
-        };
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    // Event id or URL, required unless --file is given
+    event: Option<String>,
 
-        negative_score += score;
-    }
+    // Load the event from a JSON file saved with --save instead of fetching it
+    #[clap(short, long, conflicts_with = "event")]
+    file: Option<PathBuf>,
+
+    // Save the fetched event as JSON, to replay it later with --file
+    #[clap(short, long)]
+    save: Option<PathBuf>,
+
+    // Price to prefer, in cents
+    #[clap(long)]
+    target_price: Option<i32>,
+
+    // Price range in cents, variants outside of it are excluded
+    #[clap(long)]
+    min_price: Option<i32>,
+
+    #[clap(long)]
+    max_price: Option<i32>,
+
+    // Category id or name of the whole event, can be repeated. Variants have no categories, so
+    // an event outside of the filters gets nothing reserved.
+    #[clap(long)]
+    include_category: Vec<String>,
+
+    #[clap(long)]
+    exclude_category: Vec<String>,
+
+    // Kide product type, can be repeated
+    #[clap(long)]
+    include_product_type: Vec<i32>,
+
+    #[clap(long)]
+    exclude_product_type: Vec<i32>,
+
+    // Variant name to match, fuzzy unless --use-regex is given
+    #[clap(long)]
+    target_name: Option<String>,
+
+    #[clap(long)]
+    use_regex: bool,
+
+    // Rank for an account with this eligibility profile instead of ignoring requirements
+    #[clap(long)]
+    eligibility: bool,
 
-    positive_score - negative_score*10
+    #[clap(long, requires = "eligibility")]
+    student_card: bool,
+
+    #[clap(long, requires = "eligibility")]
+    haka: bool,
+
+    // Membership id, can be repeated
+    #[clap(long, requires = "eligibility")]
+    membership: Vec<String>,
+
+    // Most the account may spend on everything it reserves in a task, in cents
+    #[clap(long, requires = "eligibility")]
+    budget: Option<i32>,
 }
 
-fn main() {
-    let mut scores: Vec<(String, isize)> = Vec::new();
-    for word in WORDS.iter() {
-        scores.push((word.to_string(), score_word(word)));
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let cli = Cli::parse();
+
+    let sale = match (&cli.file, &cli.event) {
+        (Some(file), _) => {
+            let json = fs::read_to_string(file).expect("Could not read event file");
+            serde_json::from_str::<Sale>(&json).expect("Invalid event file")
+        }
+        (None, Some(event)) => {
+            let event_id = event_id_from_url(event).expect("Invalid event url");
+            get_client()
+                .product(event_id.to_string())
+                .await
+                .expect("Could not fetch event")
+                .sale
+        }
+        (None, None) => {
+            eprintln!("Either an event or --file is required");
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(save) = &cli.save {
+        let json = serde_json::to_string_pretty(&sale).unwrap();
+        fs::write(save, json).expect("Could not save event");
+    }
+
+    let options = TaskOptions {
+        target_price: cli.target_price,
+        min_price: cli.min_price,
+        max_price: cli.max_price,
+        include_categories: cli.include_category,
+        exclude_categories: cli.exclude_category,
+        include_product_types: cli.include_product_type,
+        exclude_product_types: cli.exclude_product_type,
+        target_name: cli.target_name,
+        use_regex: cli.use_regex,
+        ..Default::default()
+    };
+    let strategy = TicketPriorityStrategy::new(options).with_categories(&sale.categories);
+
+    let eligibility = cli.eligibility.then(|| Eligibility {
+        has_student_card: cli.student_card,
+        has_haka: cli.haka,
+        membership_ids: cli.membership,
+        budget: cli.budget,
+    });
+
+    println!(
+        "{} by {}, sales from {}",
+        sale.product.name, sale.company.name, sale.product.date_sales_from
+    );
+
+    if sale.variants.is_empty() {
+        println!("No variants yet, the sale hasn't started");
+        return;
     }
 
-    scores.sort_by(|a, b| b.1.cmp(&a.1));
+    println!(
+        "{:>3}  {:>7}  {:>7}  {:>7}  {:>9}  {:>5}  name",
+        "#", "score", "name", "price", "eur", "left"
+    );
+
+    let ranking = strategy.rank(&sale.variants, eligibility.as_ref());
+    for (i, ranked) in ranking.iter().enumerate() {
+        let variant = &ranked.variant;
+        let breakdown = &ranked.breakdown;
+        let name_match = match strategy.matches_name(&variant.name) {
+            Ok(true) => "",
+            Ok(false) => " (name doesn't match)",
+            Err(e) => {
+                eprintln!("Invalid target name: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        println!(
+            "{:>3}  {:>7}  {:>7}  {:>7}  {:>9.2}  {:>5}  {}{}{}",
+            i + 1,
+            breakdown.total,
+            breakdown.name_score,
+            breakdown.price_score,
+            variant.price_per_item as f64 / 100.0,
+            variant.availability,
+            variant.name,
+            name_match,
+            if breakdown.exclusions.is_empty() {
+                String::new()
+            } else {
+                format!(" [excluded: {}]", breakdown.exclusions.join("; "))
+            },
+        );
 
-    for (word, score) in scores.iter() {
-        println!("{}: {}", word, score);
+        for keyword in &breakdown.keywords {
+            println!("{:>38}{:>+7}  {}", "", keyword.score, keyword.keyword);
+        }
     }
 }
@@ -1,8 +1,13 @@
+use crystal::auth::ApiKey;
+use crystal::crypto::initialize_keyring;
 use crystal::db::initialize_db_manager;
-use crystal::graphql::{Context, Query, Mutation, Schema};
-use crystal::queue::connect_to_queue;
+use crystal::graphql::{Context, Query, Mutation, Schema, Subscription};
+use crystal::notify::initialize_listener;
+use crystal::queue::{connect_to_queue, initialize_dispatch_queue, Queue};
+use crystal::user::User;
 use dotenvy::dotenv;
-use juniper::EmptySubscription;
+use juniper::{FieldError, Variables};
+use juniper_graphql_ws::ConnectionConfig;
 use std::env;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -15,16 +20,29 @@ use actix_web::{
     web::{self, Data},
     App, Error, HttpResponse, HttpServer, HttpRequest
 };
+use juniper_actix::subscriptions::ws_handler;
 use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
 
 #[route("/graphiql", method = "GET")]
 async fn graphiql_route() -> Result<HttpResponse, Error> {
-    graphiql_handler("/graphql", None).await
+    graphiql_handler("/graphql", Some("/subscriptions")).await
 }
 
 #[route("/playground", method = "GET")]
 async fn playground_route() -> Result<HttpResponse, Error> {
-    playground_handler("/graphql", None).await
+    playground_handler("/graphql", Some("/subscriptions")).await
+}
+
+// Accepts either `Authorization: Bearer <key>` or `X-Api-Key: <key>`. An Authorization header
+// of another scheme, e.g. added by a proxy, doesn't hide the API key.
+fn api_key(req: &HttpRequest) -> Option<&str> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    bearer.or_else(|| req.headers().get("X-Api-Key")?.to_str().ok())
 }
 
 #[route("/graphql", method = "GET", method = "POST")]
@@ -32,11 +50,88 @@ pub async fn graphql_route(
     req: HttpRequest,
     payload: web::Payload,
     schema: web::Data<Arc<Schema>>,
-    ctx: web::Data<Arc<Context>>,
+    queue: web::Data<Arc<RwLock<Queue>>>,
     ) -> Result<HttpResponse, Error> {
+        let user = match authenticate(&req).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Ok(HttpResponse::Unauthorized().finish()),
+            Err(e) => {
+                log::error!("Failed to authenticate API key: {}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        };
+
+        let ctx = Context {
+            queue: queue.get_ref().clone(),
+            user,
+        };
+
         graphql_handler(&schema, &ctx, req, payload).await
 }
 
+// Browsers can't set headers on websockets, so the key may also be sent as `apiKey` in the
+// connection init payload
+#[route("/subscriptions", method = "GET")]
+pub async fn subscriptions_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    schema: web::Data<Arc<Schema>>,
+    queue: web::Data<Arc<RwLock<Queue>>>,
+    ) -> Result<HttpResponse, Error> {
+        let header_user = match authenticate(&req).await {
+            Ok(user) => user,
+            Err(e) => {
+                log::error!("Failed to authenticate API key: {}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        };
+
+        let queue = queue.get_ref().clone();
+        let init = move |params: Variables| async move {
+            let user = match header_user {
+                Some(user) => Some(user),
+                None => {
+                    let key = params
+                        .get("apiKey")
+                        .and_then(|key| key.as_string_value())
+                        .map(|key| key.to_string());
+
+                    match key {
+                        Some(key) => match authenticate_key(&key).await {
+                            Ok(user) => user,
+                            Err(e) => return Err(FieldError::from(e)),
+                        },
+                        None => None,
+                    }
+                }
+            };
+
+            match user {
+                Some(user) => Ok(ConnectionConfig::new(Context { queue, user })),
+                None => Err(FieldError::from("Unauthorized")),
+            }
+        };
+
+        ws_handler(req, stream, schema.get_ref().clone(), init).await
+}
+
+async fn authenticate(req: &HttpRequest) -> Result<Option<User>, crystal::db::DBError> {
+    match api_key(req) {
+        Some(key) => authenticate_key(key).await,
+        None => Ok(None),
+    }
+}
+
+async fn authenticate_key(key: &str) -> Result<Option<User>, crystal::db::DBError> {
+    match ApiKey::authenticate(key).await? {
+        Some(api_key) => {
+            log::debug!("Authenticated with API key {}", api_key.name);
+            api_key.user().await
+        }
+        None => Ok(None),
+    }
+}
+
 #[actix_web::main]
 async fn main() {
     dotenv().ok();
@@ -49,33 +144,71 @@ async fn main() {
     // Initialize DB Pool for crystal operations
     initialize_db_manager(database_url.clone()).await;
 
-    let queue = RwLock::new(connect_to_queue(database_url).await);
-    let ctx = Arc::new(Context { queue });
+    // New and updated tokens are encrypted before they are stored. The keys are symmetric, so the
+    // API holds everything needed to decrypt them too, it just never does.
+    initialize_keyring();
+
+    // Relays task events recorded by the workers to subscriptions
+    initialize_listener(database_url.clone());
+
+    let queue = connect_to_queue(database_url).await;
+
+    // Scheduling a task is announced to webhooks, the workers deliver it
+    initialize_dispatch_queue(queue.clone());
+
+    let queue = Arc::new(RwLock::new(queue));
+
+    // GraphiQL and the playground are only served when explicitly enabled
+    let graphiql_enabled = env::var("GRAPHIQL_ENABLED")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+
+    // Comma separated list of origins allowed to call the API, only same-origin requests are
+    // allowed if unset
+    let allowed_origins: Vec<String> = env::var("ALLOWED_ORIGINS")
+        .map(|origins| {
+            origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    if allowed_origins.is_empty() {
+        log::info!("ALLOWED_ORIGINS not set, cross-origin requests are refused");
+    }
 
     let schema = Arc::new(Schema::new(
         Query {},
         Mutation {},
-        EmptySubscription::new(),
+        Subscription {},
     ));
 
     let server = HttpServer::new(move || {
+        let mut cors = Cors::default()
+            .allowed_methods(vec!["POST", "GET"])
+            .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
+            .allowed_header(header::CONTENT_TYPE)
+            .allowed_header("X-Api-Key")
+            .max_age(3600);
+
+        cors = allowed_origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin));
+
         App::new()
             .app_data(Data::new(schema.clone()))
-            .app_data(Data::new(ctx.clone()))
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allowed_methods(vec!["POST", "GET"])
-                    .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
-                    .allowed_header(header::CONTENT_TYPE)
-                    .supports_credentials()
-                    .max_age(3600),
-            )
+            .app_data(Data::new(queue.clone()))
+            .wrap(cors)
             .wrap(middleware::Compress::default())
             .wrap(middleware::Logger::default())
             .service(graphql_route)
-            .service(graphiql_route)
-            .service(playground_route)
+            .service(subscriptions_route)
+            .configure(|cfg| {
+                if graphiql_enabled {
+                    cfg.service(graphiql_route).service(playground_route);
+                }
+            })
     });
 
     server.bind("127.0.0.1:8080").unwrap().run().await.unwrap();
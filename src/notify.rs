@@ -0,0 +1,131 @@
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_postgres::AsyncMessage;
+use uuid::Uuid;
+
+use crate::db::create_db_connector;
+
+// Both channels are notified by triggers, see the create_notify_triggers migration
+pub const TASKS_CHANNEL: &str = "crystal_tasks";
+pub const EVENTS_CHANNEL: &str = "crystal_events";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const CHANNEL_CAPACITY: usize = 1024;
+
+static LISTENER_INSTANCE: OnceCell<broadcast::Sender<Notification>> = OnceCell::new();
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+    // A task was scheduled, updated or retried
+    Task(TaskNotification),
+    // A task event was recorded
+    Event(EventNotification),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskNotification {
+    pub id: Uuid,
+    pub scheduled_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventNotification {
+    pub id: i64,
+    pub event_id: String,
+    pub owner_id: Option<Uuid>,
+}
+
+impl Notification {
+    fn parse(channel: &str, payload: &str) -> Option<Self> {
+        let notification = match channel {
+            TASKS_CHANNEL => serde_json::from_str(payload).map(Notification::Task),
+            EVENTS_CHANNEL => serde_json::from_str(payload).map(Notification::Event),
+            _ => return None,
+        };
+
+        match notification {
+            Ok(notification) => Some(notification),
+            Err(e) => {
+                log::warn!("Malformed notification on {}: {}", channel, e);
+                None
+            }
+        }
+    }
+}
+
+// Keeps a dedicated connection listening on the crystal channels and rebroadcasts everything it
+// receives inside the process. The connection is re-established if it drops.
+pub fn initialize_listener(database_url: String) {
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+    if LISTENER_INSTANCE.set(sender.clone()).is_err() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(&database_url, &sender).await {
+                log::error!("Notification listener failed: {}", e);
+            }
+
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+// None if the listener isn't running in this process, callers should fall back to polling
+pub fn subscribe() -> Option<broadcast::Receiver<Notification>> {
+    LISTENER_INSTANCE.get().map(|sender| sender.subscribe())
+}
+
+async fn listen(
+    database_url: &str,
+    sender: &broadcast::Sender<Notification>,
+) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) =
+        tokio_postgres::connect(database_url, create_db_connector()).await?;
+
+    // The connection has to be polled for the client to make progress, so messages are drained
+    // in the background while LISTEN is issued
+    let (message_sender, mut messages) = tokio::sync::mpsc::unbounded_channel();
+    let connection_task = tokio::spawn(async move {
+        let mut stream = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = stream.next().await {
+            if message_sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    client
+        .batch_execute(&format!(
+            "LISTEN {}; LISTEN {};",
+            TASKS_CHANNEL, EVENTS_CHANNEL
+        ))
+        .await?;
+    log::info!("Listening for notifications...");
+
+    let result = loop {
+        match messages.recv().await {
+            Some(Ok(AsyncMessage::Notification(notification))) => {
+                if let Some(notification) =
+                    Notification::parse(notification.channel(), notification.payload())
+                {
+                    // Nobody listening is fine
+                    let _ = sender.send(notification);
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => break Err(e),
+            None => break Ok(()),
+        }
+    };
+
+    connection_task.abort();
+    result
+}
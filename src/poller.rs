@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::request::{get_client, RequestError};
+use crate::sale::SaleClient;
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const DEFAULT_NEAR_INTERVAL_MS: u64 = 100;
+const DEFAULT_NEAR_SECONDS: i64 = 2;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+
+static POLLER_INSTANCE: OnceCell<Poller> = OnceCell::new();
+
+// How often an event is polled. Far from the sale start every `interval`, within `near` of it
+// and after it every `near_interval`. Errors back off exponentially up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollSchedule {
+    pub interval: Duration,
+    pub near_interval: Duration,
+    pub near: chrono::Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for PollSchedule {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(DEFAULT_INTERVAL_MS),
+            near_interval: Duration::from_millis(DEFAULT_NEAR_INTERVAL_MS),
+            near: chrono::Duration::seconds(DEFAULT_NEAR_SECONDS),
+            max_backoff: Duration::from_millis(DEFAULT_MAX_BACKOFF_MS),
+        }
+    }
+}
+
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+impl PollSchedule {
+    // Read from POLL_INTERVAL_MS, POLL_NEAR_INTERVAL_MS, POLL_NEAR_SECONDS and
+    // POLL_MAX_BACKOFF_MS, anything missing keeps its default
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        Self {
+            interval: env_number("POLL_INTERVAL_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.interval),
+            near_interval: env_number("POLL_NEAR_INTERVAL_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.near_interval),
+            near: env_number("POLL_NEAR_SECONDS")
+                .map(chrono::Duration::seconds)
+                .unwrap_or(defaults.near),
+            max_backoff: env_number("POLL_MAX_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.max_backoff),
+        }
+    }
+
+    pub fn is_near(&self, starts_at: DateTime<Utc>) -> bool {
+        starts_at - Utc::now() <= self.near
+    }
+
+    pub fn interval_for(&self, starts_at: DateTime<Utc>) -> Duration {
+        if self.is_near(starts_at) {
+            self.near_interval
+        } else {
+            self.interval
+        }
+    }
+
+    // Doubles the delay for every failure in a row
+    pub fn backoff(&self, base: Duration, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(cmp::min(failures, 16));
+        cmp::min(base.saturating_mul(factor), self.max_backoff)
+    }
+}
+
+// Polls every event once per worker no matter how many tasks are waiting on it. Tasks subscribe
+// to an event and get every fetched state of it, polling stops when the last of them is gone.
+pub struct Poller {
+    schedule: PollSchedule,
+    events: Mutex<HashMap<String, watch::Sender<Option<SaleClient>>>>,
+}
+
+// Every process that scalps needs one, so it's set up on first use rather than at startup
+pub fn get_poller() -> &'static Poller {
+    POLLER_INSTANCE.get_or_init(|| Poller::new(PollSchedule::from_env()))
+}
+
+impl Poller {
+    fn new(schedule: PollSchedule) -> Self {
+        Self {
+            schedule,
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn schedule(&self) -> &PollSchedule {
+        &self.schedule
+    }
+
+    pub fn subscribe(&'static self, event_id: &str) -> Subscription {
+        let (subscription, sender) = self.join(event_id);
+
+        if let Some(sender) = sender {
+            tokio::spawn(self.poll(event_id.to_string(), sender));
+        }
+
+        subscription
+    }
+
+    // Subscribes to the event, along with the sender of a new poll loop if nothing polls the
+    // event yet
+    fn join(&self, event_id: &str) -> (Subscription, Option<watch::Sender<Option<SaleClient>>>) {
+        let mut events = self.events.lock().unwrap();
+
+        if let Some(sender) = events.get(event_id) {
+            let subscription = Subscription {
+                receiver: sender.subscribe(),
+                fresh: true,
+            };
+            return (subscription, None);
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        events.insert(event_id.to_string(), sender.clone());
+
+        let subscription = Subscription {
+            receiver,
+            fresh: true,
+        };
+        (subscription, Some(sender))
+    }
+
+    // Stops polling the event once every subscription is gone. Checked under the lock so a task
+    // subscribing right now can't miss the removal.
+    fn abandoned(&self, event_id: &str, sender: &watch::Sender<Option<SaleClient>>) -> bool {
+        let mut events = self.events.lock().unwrap();
+        if sender.receiver_count() > 0 {
+            return false;
+        }
+
+        events.remove(event_id);
+        true
+    }
+
+    async fn poll(&'static self, event_id: String, sender: watch::Sender<Option<SaleClient>>) {
+        log::debug!("Started polling {}", event_id);
+        let mut failures = 0;
+
+        loop {
+            let delay = match get_client().product(event_id.clone()).await {
+                Ok(sale_client) => {
+                    failures = 0;
+                    let delay = self
+                        .schedule
+                        .interval_for(sale_client.sale.product.date_sales_from);
+                    sender.send_replace(Some(sale_client));
+                    delay
+                }
+                Err(RequestError::RateLimited { retry_after }) => {
+                    failures += 1;
+                    let delay = retry_after
+                        .unwrap_or_else(|| self.schedule.backoff(self.schedule.interval, failures));
+                    log::warn!(
+                        "Rate limited while polling {}, waiting {} ms",
+                        event_id,
+                        delay.as_millis()
+                    );
+                    delay
+                }
+                Err(e) => {
+                    failures += 1;
+                    log::warn!("Failed to poll {}: {}", event_id, e);
+                    self.schedule.backoff(self.schedule.near_interval, failures)
+                }
+            };
+
+            if self.abandoned(&event_id, &sender) {
+                break;
+            }
+
+            tokio::time::sleep(delay).await;
+        }
+
+        log::debug!("Stopped polling {}", event_id);
+    }
+}
+
+pub struct Subscription {
+    receiver: watch::Receiver<Option<SaleClient>>,
+    // Late subscribers start from the last fetched state instead of waiting for the next one
+    fresh: bool,
+}
+
+impl Subscription {
+    // Waits for the next state of the event. None if polling has stopped.
+    pub async fn next(&mut self) -> Option<SaleClient> {
+        if self.fresh {
+            self.fresh = false;
+            if let Some(sale_client) = self.receiver.borrow_and_update().clone() {
+                return Some(sale_client);
+            }
+        }
+
+        loop {
+            self.receiver.changed().await.ok()?;
+            if let Some(sale_client) = self.receiver.borrow_and_update().clone() {
+                return Some(sale_client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polls_slowly_far_from_the_start() {
+        let schedule = PollSchedule::default();
+
+        assert_eq!(
+            schedule.interval_for(Utc::now() + chrono::Duration::minutes(5)),
+            schedule.interval
+        );
+    }
+
+    #[test]
+    fn polls_quickly_near_and_after_the_start() {
+        let schedule = PollSchedule::default();
+
+        assert_eq!(
+            schedule.interval_for(Utc::now() + chrono::Duration::seconds(1)),
+            schedule.near_interval
+        );
+        assert_eq!(
+            schedule.interval_for(Utc::now() - chrono::Duration::minutes(5)),
+            schedule.near_interval
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let schedule = PollSchedule::default();
+        let base = Duration::from_millis(100);
+
+        assert_eq!(schedule.backoff(base, 0), base);
+        assert_eq!(schedule.backoff(base, 1), Duration::from_millis(200));
+        assert_eq!(schedule.backoff(base, 3), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn subscriptions_to_an_event_share_one_poll_loop() {
+        let poller = Poller::new(PollSchedule::default());
+
+        let (first, sender) = poller.join("event");
+        let (second, none) = poller.join("event");
+        let sender = sender.unwrap();
+
+        assert!(none.is_none());
+        assert_eq!(sender.receiver_count(), 2);
+        assert_eq!(poller.events.lock().unwrap().len(), 1);
+
+        drop(first);
+        assert!(!poller.abandoned("event", &sender));
+
+        drop(second);
+        assert!(poller.abandoned("event", &sender));
+        assert!(poller.events.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backs_off_up_to_the_maximum() {
+        let schedule = PollSchedule::default();
+        let base = Duration::from_millis(100);
+
+        assert_eq!(schedule.backoff(base, 10), schedule.max_backoff);
+        assert_eq!(schedule.backoff(base, u32::MAX), schedule.max_backoff);
+    }
+}
@@ -1,9 +1,22 @@
 use fang::asynk::async_queue::AsyncQueue;
+use once_cell::sync::OnceCell;
 use postgres_native_tls::MakeTlsConnector;
 use crate::db::create_db_connector;
 
 pub type Queue = AsyncQueue<MakeTlsConnector>;
 
+static DISPATCH_QUEUE_INSTANCE: OnceCell<Queue> = OnceCell::new();
+
+// Notifications about task events (webhooks, emails) are queued on this queue. Processes that
+// never initialize it don't send any.
+pub fn initialize_dispatch_queue(queue: Queue) {
+    let _ = DISPATCH_QUEUE_INSTANCE.set(queue);
+}
+
+pub fn get_dispatch_queue() -> Option<Queue> {
+    DISPATCH_QUEUE_INSTANCE.get().cloned()
+}
+
 pub async fn connect_to_queue(database_url: String) -> Queue {
     let max_pool_size: u32 = 3;
     let mut queue = AsyncQueue::builder()
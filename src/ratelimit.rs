@@ -0,0 +1,458 @@
+use once_cell::sync::OnceCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::secret::Secret;
+
+// How long to stay away when Kide rate limits without saying for how long
+const DEFAULT_RATE_LIMITED_BACKOFF: Duration = Duration::from_secs(1);
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+static RATE_LIMITER_INSTANCE: OnceCell<RateLimiter> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Product,
+    // Reserving and reading the basket, both go to /reservations
+    Reservations,
+}
+
+impl Endpoint {
+    const ALL: [Endpoint; 2] = [Endpoint::Product, Endpoint::Reservations];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Endpoint::Product => "product",
+            Endpoint::Reservations => "reservations",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Endpoint::Product => 0,
+            Endpoint::Reservations => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    // RATE_LIMIT_<NAME> requests per second, RATE_LIMIT_<NAME>_BURST at once
+    fn from_env(name: &str, per_second: f64, burst: f64) -> Self {
+        let read = |var: String, default: f64| {
+            env::var(var)
+                .ok()
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|value| *value > 0.0)
+                .unwrap_or(default)
+        };
+
+        Self {
+            per_second: read(format!("RATE_LIMIT_{}", name), per_second),
+            burst: read(format!("RATE_LIMIT_{}_BURST", name), burst),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    pub product: Limit,
+    pub reservations: Limit,
+    // Per account token, an account reserving, verifying and falling back needs a few at once
+    pub account: Limit,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        Self {
+            product: Limit::from_env("PRODUCT", 20.0, 20.0),
+            reservations: Limit::from_env("RESERVATIONS", 20.0, 40.0),
+            account: Limit::from_env("ACCOUNT", 2.0, 6.0),
+        }
+    }
+
+    fn for_key(&self, key: &BucketKey) -> Limit {
+        match key {
+            BucketKey::Endpoint(Endpoint::Product) => self.product,
+            BucketKey::Endpoint(Endpoint::Reservations) => self.reservations,
+            BucketKey::Account(_) => self.account,
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+    // Set when Kide rate limited us, nothing goes out before it
+    blocked_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: Instant::now(),
+            blocked_until: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    // How long until a token can be taken, zero if one can be taken right away
+    fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+
+        if let Some(blocked_until) = self.blocked_until {
+            if blocked_until > now {
+                return blocked_until - now;
+            }
+            self.blocked_until = None;
+        }
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.limit.per_second)
+        }
+    }
+
+    fn block(&mut self, until: Instant) {
+        self.tokens = 0.0;
+        if !matches!(self.blocked_until, Some(blocked_until) if blocked_until > until) {
+            self.blocked_until = Some(until);
+        }
+    }
+}
+
+// Tokens are never kept around, only a hash of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Endpoint(Endpoint),
+    Account(u64),
+}
+
+fn account_key(token: &Secret) -> BucketKey {
+    let mut hasher = DefaultHasher::new();
+    token.expose().hash(&mut hasher);
+    BucketKey::Account(hasher.finish())
+}
+
+#[derive(Default)]
+struct EndpointCounters {
+    requests: AtomicU64,
+    // Requests that had to wait for our own limiter
+    throttled: AtomicU64,
+    waited_ms: AtomicU64,
+    // 429 and 503 responses from Kide
+    rate_limited: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointMetrics {
+    pub endpoint: Endpoint,
+    pub requests: u64,
+    pub throttled: u64,
+    pub waited_ms: u64,
+    pub rate_limited: u64,
+}
+
+// Keeps every process under Kide's rate limits, shared by all clients in it
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<BucketKey, TokenBucket>>,
+    counters: [EndpointCounters; 2],
+}
+
+pub fn get_rate_limiter() -> &'static RateLimiter {
+    RATE_LIMITER_INSTANCE.get_or_init(|| RateLimiter::new(RateLimits::from_env()))
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            counters: Default::default(),
+        }
+    }
+
+    fn keys(endpoint: Endpoint, token: Option<&Secret>) -> Vec<BucketKey> {
+        let mut keys = vec![BucketKey::Endpoint(endpoint)];
+        if let Some(token) = token {
+            keys.push(account_key(token));
+        }
+        keys
+    }
+
+    // Waits until both the endpoint and the account have a token to spare and takes them
+    pub async fn acquire(&self, endpoint: Endpoint, token: Option<&Secret>) {
+        let keys = Self::keys(endpoint, token);
+        let counters = &self.counters[endpoint.index()];
+        let started = Instant::now();
+        let mut throttled = false;
+
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let now = Instant::now();
+
+                // Only taken once every bucket has one, so waiting on one doesn't waste another's
+                let wait = keys
+                    .iter()
+                    .map(|key| {
+                        buckets
+                            .entry(*key)
+                            .or_insert_with(|| TokenBucket::new(self.limits.for_key(key)))
+                            .wait(now)
+                    })
+                    .max()
+                    .unwrap_or_default();
+
+                if wait.is_zero() {
+                    for key in &keys {
+                        if let Some(bucket) = buckets.get_mut(key) {
+                            bucket.tokens -= 1.0;
+                        }
+                    }
+                }
+
+                wait
+            };
+
+            if wait.is_zero() {
+                break;
+            }
+
+            throttled = true;
+            tokio::time::sleep(wait).await;
+        }
+
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if throttled {
+            let waited = started.elapsed().as_millis() as u64;
+            log::debug!(
+                "Throttled a {} request for {} ms",
+                endpoint.as_str(),
+                waited
+            );
+            counters.throttled.fetch_add(1, Ordering::Relaxed);
+            counters.waited_ms.fetch_add(waited, Ordering::Relaxed);
+        }
+    }
+
+    // Kide answered 429 or 503, holds back everything to the endpoint and from the account
+    pub fn rate_limited(
+        &self,
+        endpoint: Endpoint,
+        token: Option<&Secret>,
+        retry_after: Option<Duration>,
+    ) {
+        let backoff = retry_after.unwrap_or(DEFAULT_RATE_LIMITED_BACKOFF);
+        log::warn!(
+            "Kide rate limited a {} request, backing off for {} ms",
+            endpoint.as_str(),
+            backoff.as_millis()
+        );
+
+        self.counters[endpoint.index()]
+            .rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+
+        let until = Instant::now() + backoff;
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in Self::keys(endpoint, token) {
+            buckets
+                .entry(key)
+                .or_insert_with(|| TokenBucket::new(self.limits.for_key(&key)))
+                .block(until);
+        }
+    }
+
+    pub fn metrics(&self) -> Vec<EndpointMetrics> {
+        Endpoint::ALL
+            .iter()
+            .map(|endpoint| {
+                let counters = &self.counters[endpoint.index()];
+                EndpointMetrics {
+                    endpoint: *endpoint,
+                    requests: counters.requests.load(Ordering::Relaxed),
+                    throttled: counters.throttled.load(Ordering::Relaxed),
+                    waited_ms: counters.waited_ms.load(Ordering::Relaxed),
+                    rate_limited: counters.rate_limited.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+// Logs the throttling counters every minute there was traffic
+pub fn spawn_metrics_reporter() {
+    tokio::spawn(async {
+        let mut reported = Vec::new();
+
+        loop {
+            tokio::time::sleep(METRICS_REPORT_INTERVAL).await;
+
+            let metrics = get_rate_limiter().metrics();
+            if metrics == reported {
+                continue;
+            }
+
+            for endpoint in &metrics {
+                log::info!(
+                    "Kide {}: {} requests, {} throttled for {} ms in total, {} rate limited",
+                    endpoint.endpoint.as_str(),
+                    endpoint.requests,
+                    endpoint.throttled,
+                    endpoint.waited_ms,
+                    endpoint.rate_limited
+                );
+            }
+
+            reported = metrics;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        per_second: 2.0,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn bucket_starts_full() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT);
+
+        assert_eq!(bucket.wait(now), Duration::ZERO);
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn bucket_waits_for_the_next_token_when_empty() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.updated = now;
+        bucket.tokens = 0.5;
+
+        assert_eq!(bucket.wait(now), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.updated = now;
+        bucket.tokens = 0.0;
+
+        bucket.refill(now + Duration::from_millis(500));
+        assert_eq!(bucket.tokens, 1.0);
+
+        bucket.refill(now + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn blocked_bucket_waits_until_unblocked() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.updated = now;
+        bucket.block(now + Duration::from_secs(5));
+
+        assert_eq!(bucket.tokens, 0.0);
+        assert_eq!(bucket.wait(now), Duration::from_secs(5));
+
+        // An earlier block doesn't shorten a later one
+        bucket.block(now + Duration::from_secs(1));
+        assert_eq!(bucket.wait(now), Duration::from_secs(5));
+
+        // Refilled meanwhile, so a token is there once the block lapses
+        assert_eq!(bucket.wait(now + Duration::from_secs(5)), Duration::ZERO);
+        assert_eq!(bucket.blocked_until, None);
+    }
+
+    fn limiter(per_second: f64, burst: f64) -> RateLimiter {
+        let limit = Limit { per_second, burst };
+        RateLimiter::new(RateLimits {
+            product: limit,
+            reservations: limit,
+            account: Limit {
+                per_second,
+                burst: 1.0,
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn acquire_lets_the_burst_through() {
+        let limiter = limiter(1000.0, 3.0);
+
+        for _ in 0..3 {
+            limiter.acquire(Endpoint::Product, None).await;
+        }
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics[0].requests, 3);
+        assert_eq!(metrics[0].throttled, 0);
+    }
+
+    #[tokio::test]
+    async fn acquire_throttles_past_the_burst() {
+        let limiter = limiter(1000.0, 1.0);
+
+        limiter.acquire(Endpoint::Product, None).await;
+        limiter.acquire(Endpoint::Product, None).await;
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics[0].requests, 2);
+        assert_eq!(metrics[0].throttled, 1);
+        // The other endpoint has its own bucket
+        assert_eq!(metrics[1].requests, 0);
+    }
+
+    #[tokio::test]
+    async fn acquire_takes_from_the_account_too() {
+        let limiter = limiter(1000.0, 10.0);
+        let token = Secret::new("token".to_string());
+
+        limiter.acquire(Endpoint::Reservations, Some(&token)).await;
+        limiter.acquire(Endpoint::Reservations, Some(&token)).await;
+        limiter.acquire(Endpoint::Reservations, None).await;
+
+        // Only the second request of the account had to wait for it
+        assert_eq!(limiter.metrics()[1].throttled, 1);
+    }
+
+    #[tokio::test]
+    async fn acquire_holds_back_after_being_rate_limited() {
+        let limiter = limiter(1000.0, 10.0);
+        let backoff = Duration::from_millis(50);
+
+        let started = Instant::now();
+        limiter.rate_limited(Endpoint::Product, None, Some(backoff));
+        limiter.acquire(Endpoint::Product, None).await;
+
+        assert!(started.elapsed() >= backoff);
+        assert_eq!(limiter.metrics()[0].rate_limited, 1);
+    }
+}
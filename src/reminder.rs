@@ -0,0 +1,142 @@
+use chrono::{DateTime, Duration, Utc};
+use fang::async_trait;
+use fang::asynk::async_queue::AsyncQueueable;
+use fang::serde::{Deserialize, Serialize};
+use fang::typetag;
+use fang::AsyncRunnable;
+use fang::FangError;
+use fang::Scheduled;
+use std::env;
+use uuid::Uuid;
+
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::result::TaskResult;
+use crate::task::TaskKey;
+
+// Minutes before a hold lapses to remind about it, HOLD_REMINDER_MINUTES overrides it
+const DEFAULT_REMINDER_MINUTES: i64 = 5;
+
+pub fn reminder_lead() -> Duration {
+    let minutes = env::var("HOLD_REMINDER_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(DEFAULT_REMINDER_MINUTES);
+
+    Duration::minutes(minutes.max(0))
+}
+
+// Reminds that the tickets of a task result are about to be lost unless checked out
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct HoldReminderTask {
+    pub result_id: i64,
+    pub remind_at: DateTime<Utc>,
+    // Owner of the task that got the hold, the task itself may be long gone by the reminder
+    #[serde(default)]
+    pub owner_id: Option<Uuid>,
+}
+
+impl HoldReminderTask {
+    pub fn new(result_id: i64, remind_at: DateTime<Utc>, owner_id: Option<Uuid>) -> Self {
+        Self {
+            result_id,
+            remind_at,
+            owner_id,
+        }
+    }
+}
+
+#[async_trait]
+#[typetag::serde]
+impl AsyncRunnable for HoldReminderTask {
+    async fn run(&self, _queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+        let result = match TaskResult::from_id(self.result_id).await? {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+
+        // The hold may have been released or checked out since the reminder was scheduled
+        let expires_at = match result.expires_at {
+            Some(expires_at) if expires_at > Utc::now() && result.granted != Some(0) => expires_at,
+            _ => return Ok(()),
+        };
+
+        TaskEvent::emit(
+            &TaskKey::new(result.event_id, self.owner_id),
+            TaskEventKind::HoldExpiring,
+            format!(
+                "Hold on {} expires at {}, check out before then",
+                result.variant_name.unwrap_or_default(),
+                expires_at
+            ),
+            result.account,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    fn cron(&self) -> Option<Scheduled> {
+        Some(Scheduled::ScheduleOnce(self.remind_at))
+    }
+
+    fn uniq(&self) -> bool {
+        true
+    }
+
+    fn max_retries(&self) -> i32 {
+        2
+    }
+
+    fn task_type(&self) -> String {
+        "common".to_string()
+    }
+}
+
+// Schedules a reminder for every hold of the event that doesn't have one yet
+pub async fn schedule_hold_reminders(queue: &mut dyn AsyncQueueable, task: &TaskKey) {
+    let results = match TaskResult::awaiting_reminder(task).await {
+        Ok(results) => results,
+        Err(e) => {
+            log::warn!("Failed to fetch holds of {}: {}", task.event_id, e);
+            return;
+        }
+    };
+
+    let lead = reminder_lead();
+
+    for result in results {
+        let expires_at = match result.expires_at {
+            Some(expires_at) => expires_at,
+            None => continue,
+        };
+
+        let remind_at = expires_at - lead;
+        let reminder = HoldReminderTask::new(result.id, remind_at, task.owner_id);
+
+        // Holds lapsing sooner than the lead are reminded about right away
+        let scheduled = if remind_at > Utc::now() {
+            queue.schedule_task(&reminder as &dyn AsyncRunnable).await
+        } else {
+            queue.insert_task(&reminder as &dyn AsyncRunnable).await
+        };
+
+        if let Err(e) = scheduled {
+            log::warn!(
+                "Failed to schedule a reminder for result {}: {:?}",
+                result.id,
+                e
+            );
+            continue;
+        }
+
+        if let Err(e) = TaskResult::set_reminder(result.id, remind_at).await {
+            log::warn!(
+                "Failed to store the reminder of result {}: {}",
+                result.id,
+                e
+            );
+        }
+    }
+}
@@ -1,7 +1,29 @@
+use crate::ratelimit::{get_rate_limiter, Endpoint};
 use crate::sale::{Sale, SaleClient};
+use crate::secret::Secret;
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Duration;
 
 const KIDE_API_BASE_URL: &str = "https://api.kide.app/api/";
+// Rate limits shorter than this are waited out and retried instead of failing the request
+const MAX_INLINE_RETRY_WAIT: Duration = Duration::from_secs(2);
+const DEFAULT_USER_AGENT: &str = concat!("crystal/", env!("CARGO_PKG_VERSION"));
+
+static CLIENT_INSTANCE: OnceCell<Client> = OnceCell::new();
+
+// Event URLs look like https://kide.app/events/<id>, the id is all we need
+pub fn event_id_from_url(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next()?;
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|id| !id.is_empty())
+}
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -9,6 +31,67 @@ pub struct ProductResponse {
     pub model: Sale,
 }
 
+// The basket of the account the token belongs to. Only the fields we need, the API returns a lot
+// more about every reservation.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BasketResponse {
+    pub model: Basket,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Basket {
+    // Required, a basket shaped differently than expected must fail verification instead of
+    // reading as empty and failing every reservation over to the next variant
+    pub reservations: Vec<BasketReservation>,
+    // Seconds until the holds in the basket lapse, for reservations without their own expiry
+    #[serde(default)]
+    pub time_until_expires: Option<i64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BasketReservation {
+    pub inventory_id: String,
+    #[serde(alias = "reservedQuantity")]
+    pub quantity: i64,
+    #[serde(default, alias = "dateExpires")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Basket {
+    // Quantity of the inventory held in the basket
+    pub fn held(&self, inventory_id: &str) -> i64 {
+        self.reservations
+            .iter()
+            .filter(|held| held.inventory_id == inventory_id)
+            .map(|held| held.quantity)
+            .sum()
+    }
+
+    // When the latest hold of the inventory lapses, the basket's expiry for holds without their
+    // own. None if nothing of the inventory is held.
+    pub fn expires_at(&self, inventory_id: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let held: Vec<&BasketReservation> = self
+            .reservations
+            .iter()
+            .filter(|held| held.inventory_id == inventory_id)
+            .collect();
+        if held.is_empty() {
+            return None;
+        }
+
+        held.iter()
+            .filter_map(|held| held.expires_at)
+            .max()
+            .or_else(|| {
+                self.time_until_expires
+                    .map(|seconds| now + chrono::Duration::seconds(seconds))
+            })
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BatchReservation {
@@ -39,22 +122,207 @@ impl BatchReservation {
     }
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(thiserror::Error, Debug)]
+pub enum RequestError {
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+
+    // Kide answered 429 or 503, nothing should be sent before `retry_after` if it was given
+    #[error("Rate limited by Kide")]
+    RateLimited { retry_after: Option<Duration> },
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+fn is_rate_limited(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    // HTTP/2 when the server offers it, HTTP/1.1 otherwise
+    Auto,
+    Http1,
+    // HTTP/2 without negotiating it first
+    Http2,
+}
+
+impl HttpVersion {
+    pub fn from_env(version: &str) -> Option<Self> {
+        match version {
+            "auto" => Some(HttpVersion::Auto),
+            "1" | "1.1" | "http1" => Some(HttpVersion::Http1),
+            "2" | "http2" => Some(HttpVersion::Http2),
+            _ => None,
+        }
+    }
+}
+
+// How the client talks to Kide. Read from KIDE_CONNECT_TIMEOUT_MS, KIDE_TIMEOUT_MS,
+// KIDE_POOL_IDLE_SECONDS, KIDE_POOL_MAX_IDLE, KIDE_KEEPALIVE_SECONDS, KIDE_HTTP_VERSION and
+// KIDE_USER_AGENT, anything missing keeps its default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    // For the whole request, reqwest has no separate read timeout
+    pub timeout: Duration,
+    pub pool_idle_timeout: Duration,
+    pub pool_max_idle_per_host: usize,
+    pub tcp_keepalive: Duration,
+    pub http_version: HttpVersion,
+    pub user_agent: String,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 32,
+            tcp_keepalive: Duration::from_secs(30),
+            http_version: HttpVersion::Auto,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+impl ClientConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        Self {
+            connect_timeout: env_number("KIDE_CONNECT_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.connect_timeout),
+            timeout: env_number("KIDE_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.timeout),
+            pool_idle_timeout: env_number("KIDE_POOL_IDLE_SECONDS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.pool_idle_timeout),
+            pool_max_idle_per_host: env_number("KIDE_POOL_MAX_IDLE")
+                .map(|idle| idle as usize)
+                .unwrap_or(defaults.pool_max_idle_per_host),
+            tcp_keepalive: env_number("KIDE_KEEPALIVE_SECONDS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.tcp_keepalive),
+            http_version: env::var("KIDE_HTTP_VERSION")
+                .ok()
+                .and_then(|version| {
+                    let parsed = HttpVersion::from_env(&version);
+                    if parsed.is_none() {
+                        log::warn!(
+                            "Ignoring KIDE_HTTP_VERSION={}, expected auto, 1 or 2",
+                            version
+                        );
+                    }
+                    parsed
+                })
+                .unwrap_or(defaults.http_version),
+            user_agent: env::var("KIDE_USER_AGENT").unwrap_or(defaults.user_agent),
+        }
+    }
+}
+
+// Only built by `Client::new`, a default reqwest client would skip the configured timeouts and
+// pooling
+#[derive(Debug, Clone)]
 pub struct Client {
     client: reqwest::Client,
 }
 
+// The client every task, query and command shares, so connections to Kide are pooled and kept
+// alive between them
+pub fn get_client() -> &'static Client {
+    CLIENT_INSTANCE.get_or_init(|| {
+        let config = ClientConfig::from_env();
+        log::debug!("Initializing client: {:?}", config);
+        Client::new(&config)
+    })
+}
+
 impl Client {
-    pub fn new() -> Self {
+    pub fn new(config: &ClientConfig) -> Self {
+        let builder = reqwest::Client::builder()
+            .gzip(true)
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.timeout)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .tcp_keepalive(config.tcp_keepalive)
+            .user_agent(config.user_agent.clone());
+
+        let builder = match config.http_version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+
         Client {
-            client: reqwest::Client::builder().gzip(true).build().unwrap(),
+            client: builder.build().expect("Failed to build the HTTP client"),
+        }
+    }
+
+    // Sends the request once the rate limiter lets it through. When Kide rate limits it, the
+    // limiter holds everything back for as long as Kide asks, and a short wait is retried once
+    // right here. A POST answered with 503 may still have gone through, so it's only retried
+    // after a 429.
+    async fn send(
+        &self,
+        endpoint: Endpoint,
+        token: Option<&Secret>,
+        request: RequestBuilder,
+    ) -> Result<Response, RequestError> {
+        let limiter = get_rate_limiter();
+        let request = request.build()?;
+        let mut retried = false;
+
+        loop {
+            limiter.acquire(endpoint, token).await;
+
+            // Bodies are always JSON, which can be cloned
+            let attempt = request.try_clone().expect("Request body can't be cloned");
+            let response = self.client.execute(attempt).await?;
+            log::trace!("Response: {:#?}", response);
+
+            if !is_rate_limited(response.status()) {
+                return Ok(response);
+            }
+
+            let retry_after = retry_after(&response);
+            limiter.rate_limited(endpoint, token, retry_after);
+
+            let retryable = request.method() != Method::POST
+                || response.status() == StatusCode::TOO_MANY_REQUESTS;
+            match retry_after {
+                Some(wait) if retryable && !retried && wait <= MAX_INLINE_RETRY_WAIT => {
+                    retried = true
+                }
+                _ => return Err(RequestError::RateLimited { retry_after }),
+            }
         }
     }
 
-    pub async fn product(&self, uid: String) -> Result<SaleClient, reqwest::Error> {
+    pub async fn product(&self, uid: String) -> Result<SaleClient, RequestError> {
         let url = format!("{}products/{}", KIDE_API_BASE_URL, uid);
-        let response = self.client.get(&url).send().await?;
-        log::trace!("Response: {:#?}", response);
+        let response = self
+            .send(Endpoint::Product, None, self.client.get(&url))
+            .await?;
 
         let response_document: ProductResponse = response.json().await?;
         log::trace!("Response document: {:#?}", response_document);
@@ -65,26 +333,55 @@ impl Client {
         });
     }
 
+    // Returns the basket the reservation endpoint answers with, None if the body isn't one. The
+    // reservation went through either way.
     pub async fn reserve(
         &self,
         reservation: &BatchReservation,
-        token: String,
-    ) -> Result<(), reqwest::Error> {
+        token: &Secret,
+    ) -> Result<Option<Basket>, RequestError> {
         log::debug!("Reserving reservation: {:?}", reservation);
 
         let url = format!("{}reservations", KIDE_API_BASE_URL);
 
-        let response = self
+        let request = self
             .client
             .post(&url)
-            .header("Authorization", format!("Bearer {}", token))
-            .json(reservation)
-            .send()
+            .header("Authorization", format!("Bearer {}", token.expose()))
+            .json(reservation);
+        let response = self
+            .send(Endpoint::Reservations, Some(token), request)
             .await?;
 
-        log::trace!("Response: {:#?}", response);
-        log::trace!("Response body: {:#?}", response.text().await?);
+        // Rejected reservations have to fail, callers act on what they think they hold
+        let response = response.error_for_status()?;
+        let body = response.text().await?;
+        log::trace!("Response body: {:#?}", body);
+
+        match serde_json::from_str::<BasketResponse>(&body) {
+            Ok(response_document) => Ok(Some(response_document.model)),
+            Err(e) => {
+                log::debug!("Reservation response is not a basket: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn basket(&self, token: &Secret) -> Result<Basket, RequestError> {
+        let url = format!("{}reservations", KIDE_API_BASE_URL);
+
+        let request = self
+            .client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token.expose()));
+        let response = self
+            .send(Endpoint::Reservations, Some(token), request)
+            .await?
+            .error_for_status()?;
+
+        let response_document: BasketResponse = response.json().await?;
+        log::trace!("Response document: {:#?}", response_document);
 
-        Ok(())
+        Ok(response_document.model)
     }
 }
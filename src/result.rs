@@ -0,0 +1,227 @@
+use chrono::{DateTime, Utc};
+use juniper::GraphQLObject;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::api::Variant;
+use crate::db::{get_db_manager, DBError};
+use crate::strategy::ScoreBreakdown;
+use crate::task::TaskKey;
+
+#[derive(Debug, Clone, PartialEq, GraphQLObject)]
+#[graphql(description = "What a task chose for an account, and why")]
+pub struct TaskResult {
+    #[graphql(ignore)]
+    pub id: i64,
+    pub event_id: String,
+    // Owner of the task that recorded the result
+    #[graphql(ignore)]
+    pub owner_id: Option<Uuid>,
+    pub account: Option<Uuid>,
+    pub variant_id: Option<String>,
+    pub variant_name: Option<String>,
+    // Every variant in the order they were ranked, the chosen one is the first not excluded
+    pub ranking: Vec<ScoreBreakdown>,
+    // Quantity reserved, and how much of it was found in the basket afterwards. Granted is
+    // missing when the basket couldn't be checked.
+    pub requested: Option<i32>,
+    pub granted: Option<i32>,
+    // When the hold lapses unless the tickets are checked out
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'a> TryFrom<&'a Row> for TaskResult {
+    type Error = DBError;
+
+    fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        let ranking: serde_json::Value = row.try_get("ranking")?;
+
+        Ok(Self {
+            id: row.try_get("id")?,
+            event_id: row.try_get("event_id")?,
+            owner_id: row.try_get("owner_uuid")?,
+            account: row.try_get("account_uuid")?,
+            variant_id: row.try_get("variant_id")?,
+            variant_name: row.try_get("variant_name")?,
+            // Rankings written by an older worker are not worth failing the whole result over
+            ranking: serde_json::from_value(ranking).unwrap_or_default(),
+            requested: row.try_get("requested")?,
+            granted: row.try_get("granted")?,
+            expires_at: row.try_get("expires_at")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+impl TaskResult {
+    // Like task events, failing to record a result must never fail the task itself. Returns the
+    // id of the result for `reconcile`, if it could be recorded.
+    pub async fn record(
+        task: &TaskKey,
+        account: Option<Uuid>,
+        chosen: Option<&Variant>,
+        ranking: &[ScoreBreakdown],
+    ) -> Option<i64> {
+        let ranking = match serde_json::to_value(ranking) {
+            Ok(ranking) => ranking,
+            Err(e) => {
+                log::warn!("Failed to serialize ranking for {}: {}", task.event_id, e);
+                return None;
+            }
+        };
+
+        let db_manager = get_db_manager();
+        let result = db_manager
+            .query_one(
+                "INSERT INTO task_results (event_id, owner_uuid, account_uuid, variant_id, \
+                 variant_name, price_per_item, ranking) VALUES ($1, $2, $3, $4, $5, $6, $7) \
+                 RETURNING id",
+                &[
+                    &task.event_id,
+                    &task.owner_id,
+                    &account,
+                    &chosen.map(|variant| variant.id.clone()),
+                    &chosen.map(|variant| variant.name.clone()),
+                    &chosen.map(|variant| variant.price_per_item),
+                    &ranking,
+                ],
+            )
+            .await;
+
+        match result {
+            Ok(row) => row.try_get("id").ok(),
+            Err(e) => {
+                log::warn!("Failed to record task result for {}: {}", task.event_id, e);
+                None
+            }
+        }
+    }
+
+    // Stores the reconciled quantities of a recorded result, and when the hold lapses
+    pub async fn reconcile(
+        id: Option<i64>,
+        requested: i64,
+        granted: Option<i64>,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let requested = requested as i32;
+        let granted = granted.map(|granted| granted as i32);
+
+        let db_manager = get_db_manager();
+        let result = db_manager
+            .execute(
+                "UPDATE task_results SET requested = $1, granted = $2, expires_at = $3 WHERE id = $4",
+                &[&requested, &granted, &expires_at, &id],
+            )
+            .await;
+
+        if let Err(e) = result {
+            log::warn!("Failed to reconcile task result {}: {}", id, e);
+        }
+    }
+
+    // Marks a result as given back, the tickets are no longer held and nothing will lapse
+    pub async fn release(id: Option<i64>) {
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let db_manager = get_db_manager();
+        let result = db_manager
+            .execute(
+                "UPDATE task_results SET granted = 0, expires_at = NULL WHERE id = $1",
+                &[&id],
+            )
+            .await;
+
+        if let Err(e) = result {
+            log::warn!("Failed to release task result {}: {}", id, e);
+        }
+    }
+
+    pub async fn from_id(id: i64) -> Result<Option<TaskResult>, DBError> {
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_opt("SELECT * FROM task_results WHERE id = $1", &[&id])
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(TaskResult::try_from(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Results of the task holding tickets that haven't lapsed yet and have no reminder
+    pub async fn awaiting_reminder(task: &TaskKey) -> Result<Vec<TaskResult>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM task_results \
+                 WHERE event_id = $1 AND owner_uuid IS NOT DISTINCT FROM $2 \
+                 AND expires_at > now() AND COALESCE(granted, 1) > 0 \
+                 AND remind_at IS NULL ORDER BY id",
+                &[&task.event_id, &task.owner_id],
+            )
+            .await?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(TaskResult::try_from(&row)?);
+        }
+
+        Ok(results)
+    }
+
+    pub async fn set_reminder(id: i64, remind_at: DateTime<Utc>) -> Result<(), DBError> {
+        let db_manager = get_db_manager();
+        db_manager
+            .execute(
+                "UPDATE task_results SET remind_at = $1 WHERE id = $2",
+                &[&remind_at, &id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    // What the account has spent in the task so far, in cents. Results that couldn't be checked
+    // against the basket count with what was requested.
+    pub async fn spent(task: &TaskKey, account: Uuid) -> Result<i64, DBError> {
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_one(
+                "SELECT COALESCE(SUM(price_per_item * COALESCE(granted, requested, 0)), 0)::BIGINT \
+                 AS spent FROM task_results WHERE event_id = $1 \
+                 AND owner_uuid IS NOT DISTINCT FROM $2 AND account_uuid = $3",
+                &[&task.event_id, &task.owner_id, &account],
+            )
+            .await?;
+
+        Ok(row.try_get("spent")?)
+    }
+
+    pub async fn for_task(task: &TaskKey) -> Result<Vec<TaskResult>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM task_results WHERE event_id = $1 \
+                 AND owner_uuid IS NOT DISTINCT FROM $2 ORDER BY id",
+                &[&task.event_id, &task.owner_id],
+            )
+            .await?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(TaskResult::try_from(&row)?);
+        }
+
+        Ok(results)
+    }
+}
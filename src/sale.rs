@@ -1,8 +1,16 @@
-use crate::api::{Category, Company, Product, Variant};
-use crate::request::{BatchReservation, Client, VariantReservation};
+use crate::account::Eligibility;
+use crate::api::{format_cents, Category, Company, Product, Variant};
+use crate::db::DBError;
+use crate::request::{Basket, BatchReservation, Client, RequestError, VariantReservation};
+use crate::secret::Secret;
 use crate::strategy::{Quantity, TicketPriorityStrategy};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 
+// Variants opening within this much of now count as open, so a clock slightly behind Kide's
+// doesn't hide variants right as they open
+const SALE_START_TOLERANCE_SECONDS: i64 = 5;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Sale {
@@ -13,46 +21,175 @@ pub struct Sale {
     pub is_haka_required: bool,
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(thiserror::Error, Debug)]
+pub enum ReservationError {
+    #[error("Request failed: {0}")]
+    Request(#[from] RequestError),
+
+    #[error(
+        "Reservation would cost {}, over the remaining budget of {}",
+        format_cents(*.total),
+        format_cents(*.budget)
+    )]
+    OverBudget { total: i64, budget: i64 },
+
+    #[error("Database error: {0}")]
+    DBError(#[from] DBError),
+}
+
+// Total of the batch, priced with the variants of the sale
+fn batch_total(variants: &[Variant], reservations: &[VariantReservation]) -> i64 {
+    reservations
+        .iter()
+        .filter_map(|reservation| {
+            variants
+                .iter()
+                .find(|variant| variant.inventory_id == reservation.inventory_id)
+                .map(|variant| variant.price_per_item * reservation.quantity)
+        })
+        .sum()
+}
+
+// Only what the basket gained since `before` was granted, holds the account already had, from
+// before the task or an earlier round, aren't the reservation's. The expiry comes from the
+// reservation's own response, the basket read afterwards only if that didn't have one.
+pub fn reconcile(
+    before: &Basket,
+    after: &Basket,
+    requested: &[VariantReservation],
+    response: Option<&Basket>,
+    now: DateTime<Utc>,
+) -> Vec<Reconciliation> {
+    requested
+        .iter()
+        .map(|reservation| {
+            let inventory_id = &reservation.inventory_id;
+            let added = after.held(inventory_id) - before.held(inventory_id);
+            let granted = added.clamp(0, reservation.quantity.max(0));
+
+            Reconciliation {
+                inventory_id: inventory_id.clone(),
+                requested: reservation.quantity,
+                granted,
+                expires_at: if granted > 0 {
+                    reserved_until(response, inventory_id, now)
+                        .or_else(|| after.expires_at(inventory_id, now))
+                } else {
+                    None
+                },
+            }
+        })
+        .collect()
+}
+
+// When the reservation response says the hold of the inventory lapses
+pub fn reserved_until(
+    response: Option<&Basket>,
+    inventory_id: &str,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    response.and_then(|response| response.expires_at(inventory_id, now))
+}
+
+fn check_budget(total: i64, budget: Option<i64>) -> Result<(), ReservationError> {
+    match budget {
+        Some(budget) if total > budget => Err(ReservationError::OverBudget { total, budget }),
+        _ => Ok(()),
+    }
+}
+
+// What was asked for a variant compared to what the basket actually holds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reconciliation {
+    pub inventory_id: String,
+    pub requested: i64,
+    pub granted: i64,
+    // When the hold lapses unless checked out, if the basket says
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
 pub struct SaleClient {
     pub sale: Sale,
     pub client: Client,
 }
 
 impl SaleClient {
-    pub async fn reserve_fuzzy(&self, token: String, strategy: &impl Quantity, priority_strategy: &TicketPriorityStrategy) {
-        // This could be performed in scalp.rs a single time, let's do that if performance suffers
+    pub async fn reserve(
+        &self,
+        variant: &Variant,
+        token: &Secret,
+        strategy: &impl Quantity,
+        budget: Option<i64>,
+    ) -> Result<Option<Basket>, ReservationError> {
+        let variant_reservation = variant.to_reservation(strategy);
+        check_budget(
+            variant.price_per_item * variant_reservation.quantity,
+            budget,
+        )?;
 
-        let variant = priority_strategy.choose(&self.sale.variants);
+        let batch = BatchReservation::create(&variant_reservation);
 
-        if let Some(variant) = variant {
-            self.reserve(&variant, token, strategy).await;
-        } else {
-            println!("No variants to reserve");
+        match self.client.reserve(&batch, token).await {
+            Ok(response) => {
+                log::debug!("Reserved variant {}", variant.inventory_id);
+                Ok(response)
+            }
+            Err(e) => {
+                log::warn!("Failed to reserve: {}", e);
+                Err(e.into())
+            }
         }
     }
 
-    pub async fn reserve(&self, variant: &Variant, token: String, strategy: &impl Quantity) {
-        let variant_reservation = variant.to_reservation(strategy);
+    // What the account holds before reserving, so verifying only counts what the reservation
+    // adds. None if the basket couldn't be read, the reservation then can't be verified.
+    pub async fn snapshot(&self, token: &Secret) -> Option<Basket> {
+        match self.client.basket(token).await {
+            Ok(basket) => Some(basket),
+            Err(e) => {
+                log::warn!("Could not read the basket before reserving: {}", e);
+                None
+            }
+        }
+    }
 
-        let batch = BatchReservation::create(&variant_reservation);
+    // Re-reads the basket to check what the reservations actually got. A 2xx from the reservation
+    // endpoint doesn't guarantee the hold exists. Returns the basket as read, the snapshot for the
+    // next reservation of the account.
+    pub async fn verify(
+        &self,
+        token: &Secret,
+        before: &Basket,
+        requested: &[VariantReservation],
+        response: Option<&Basket>,
+    ) -> Result<(Vec<Reconciliation>, Basket), RequestError> {
+        let after = self.client.basket(token).await?;
 
-        match self.client.reserve(&batch, token).await {
-            Ok(_) => println!("Reserved variant {}", variant.inventory_id),
-            Err(e) => println!("Error: {}", e), // For now we don't care about errors, in future
-                                                // we'd have retry logic
-        }
+        Ok((
+            reconcile(before, &after, requested, response, Utc::now()),
+            after,
+        ))
     }
 
-    pub async fn reserve_all(&self, token: String, strategy: &impl Quantity) {
+    // Reserves every variant the strategy doesn't exclude for the account. Returns what was
+    // requested, nothing if there was nothing to reserve. `budget` is what the account has left
+    // to spend.
+    pub async fn reserve_all(
+        &self,
+        token: &Secret,
+        strategy: &impl Quantity,
+        priority_strategy: &TicketPriorityStrategy,
+        eligibility: &Eligibility,
+        budget: Option<i64>,
+    ) -> Result<(Vec<VariantReservation>, Option<Basket>), ReservationError> {
         let mut total_quantity = 0;
-        let reservations: Vec<VariantReservation> = self
-            .sale
-            .variants
+        let reservations: Vec<VariantReservation> = priority_strategy
+            .rank(&self.sale.variants, Some(eligibility))
             .iter()
-            .filter_map(|variant| {
-                if variant.availability > 0 {
-                    let reservation = variant.to_reservation(strategy);
+            .filter_map(|ranked| {
+                if ranked.breakdown.excluded.is_none() {
+                    let reservation = ranked.variant.to_reservation(strategy);
                     total_quantity += reservation.quantity;
 
                     Some(reservation)
@@ -65,30 +202,55 @@ impl SaleClient {
         if self.sale.product.max_total_reservations_per_checkout > 0
             && total_quantity > self.sale.product.max_total_reservations_per_checkout
         {
-            println!(
+            log::warn!(
                 "Total quantity {} exceeds max total reservations per checkout {}",
-                total_quantity, self.sale.product.max_total_reservations_per_checkout
+                total_quantity,
+                self.sale.product.max_total_reservations_per_checkout
             );
         }
 
         if reservations.is_empty() {
-            println!("No variants to reserve");
-            return;
+            log::debug!("No variants to reserve");
+            return Ok((reservations, None));
         }
 
+        check_budget(batch_total(&self.sale.variants, &reservations), budget)?;
+
         let batch = BatchReservation {
             to_create: reservations,
             to_cancel: vec![],
         };
 
         match self.client.reserve(&batch, token).await {
-            Ok(_) => log::debug!("Reserved all variants"),
-            Err(e) => println!("Error: {}", e),
+            Ok(response) => {
+                log::debug!("Reserved all variants");
+                Ok((batch.to_create, response))
+            }
+            Err(e) => {
+                log::warn!("Failed to reserve: {}", e);
+                Err(e.into())
+            }
         }
     }
 }
 
+impl Sale {
+    // Variants whose sale has started, the others are never reserved before their start
+    pub fn open_variants(&self, now: DateTime<Utc>) -> Vec<Variant> {
+        self.variants
+            .iter()
+            .filter(|variant| variant.sales_started(now))
+            .cloned()
+            .collect()
+    }
+}
+
 impl Variant {
+    // Variants can open later than the product they belong to
+    pub fn sales_started(&self, now: DateTime<Utc>) -> bool {
+        self.date_sales_from <= now + Duration::seconds(SALE_START_TOLERANCE_SECONDS)
+    }
+
     pub fn to_reservation(&self, strategy: &impl Quantity) -> VariantReservation {
         VariantReservation {
             inventory_id: self.inventory_id.clone(),
@@ -96,3 +258,112 @@ impl Variant {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::BasketReservation;
+
+    fn basket(held: &[(&str, i64)]) -> Basket {
+        Basket {
+            reservations: held
+                .iter()
+                .map(|(inventory_id, quantity)| BasketReservation {
+                    inventory_id: inventory_id.to_string(),
+                    quantity: *quantity,
+                    ..Default::default()
+                })
+                .collect(),
+            time_until_expires: Some(600),
+        }
+    }
+
+    fn requested(inventory_id: &str, quantity: i64) -> Vec<VariantReservation> {
+        vec![VariantReservation {
+            inventory_id: inventory_id.to_string(),
+            quantity,
+        }]
+    }
+
+    #[test]
+    fn grants_what_the_basket_gained() {
+        let now = Utc::now();
+        let reconciled = reconcile(
+            &basket(&[]),
+            &basket(&[("a", 2)]),
+            &requested("a", 2),
+            None,
+            now,
+        );
+
+        assert_eq!(reconciled[0].granted, 2);
+        assert_eq!(reconciled[0].expires_at, Some(now + Duration::seconds(600)));
+    }
+
+    #[test]
+    fn holds_from_before_are_not_granted() {
+        let before = basket(&[("a", 2)]);
+        let reconciled = reconcile(&before, &before, &requested("a", 2), None, Utc::now());
+
+        assert_eq!(reconciled[0].granted, 0);
+        assert_eq!(reconciled[0].expires_at, None);
+    }
+
+    #[test]
+    fn grants_no_more_than_requested() {
+        let reconciled = reconcile(
+            &basket(&[("a", 1)]),
+            &basket(&[("a", 1), ("a", 5)]),
+            &requested("a", 2),
+            None,
+            Utc::now(),
+        );
+
+        assert_eq!(reconciled[0].granted, 2);
+    }
+
+    #[test]
+    fn holds_that_lapsed_meanwhile_grant_nothing() {
+        let reconciled = reconcile(
+            &basket(&[("a", 3)]),
+            &basket(&[("a", 1)]),
+            &requested("a", 2),
+            None,
+            Utc::now(),
+        );
+
+        assert_eq!(reconciled[0].granted, 0);
+    }
+
+    #[test]
+    fn expiry_comes_from_the_reservation_response() {
+        let now = Utc::now();
+        let expires_at = now + Duration::seconds(900);
+        let response = Basket {
+            reservations: vec![BasketReservation {
+                inventory_id: "a".to_string(),
+                quantity: 2,
+                expires_at: Some(expires_at),
+            }],
+            time_until_expires: None,
+        };
+        let reconciled = reconcile(
+            &basket(&[]),
+            &basket(&[("a", 2)]),
+            &requested("a", 2),
+            Some(&response),
+            now,
+        );
+
+        assert_eq!(reconciled[0].expires_at, Some(expires_at));
+    }
+
+    #[test]
+    fn no_expiry_for_inventory_missing_from_the_response() {
+        assert_eq!(
+            reserved_until(Some(&basket(&[("b", 1)])), "a", Utc::now()),
+            None
+        );
+        assert_eq!(reserved_until(None, "a", Utc::now()), None);
+    }
+}
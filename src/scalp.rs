@@ -1,34 +1,160 @@
-use chrono::Utc;
-use fang::FangError;
+use chrono::{DateTime, Utc};
+use fang::asynk::async_queue::AsyncQueueable;
+use fang::serde::{Deserialize, Serialize};
+use fang::{AsyncRunnable, FangError};
 use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 use crate::account::{AccountIDList, KideAccount};
-use crate::request::Client;
-use crate::sale::SaleClient;
-use crate::strategy::{Count, TicketPriorityStrategy};
-use crate::task::TaskOptions;
+use crate::api::Variant;
+use crate::db::DBError;
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::group::{book_group, DEFAULT_GROUP_DEADLINE_SECONDS};
+use crate::poller::get_poller;
+use crate::reminder::schedule_hold_reminders;
+use crate::request::{get_client, Client, RequestError};
+use crate::result::TaskResult;
+use crate::sale::{reserved_until, ReservationError, SaleClient};
+use crate::secret::Secret;
+use crate::strategy::{Allocation, Count, ScoreBreakdown, TicketPriorityStrategy};
+use crate::task::{TaskKey, TaskOptions, VariantRoundTask};
 
+// How long to keep polling for variants that should have opened by now
+const ROUND_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+// How many other variants to try for an account when its reservation doesn't stick
+const MAX_FALLBACKS: usize = 2;
+// Restocks are polled politely, they trickle in over minutes rather than milliseconds
+const DEFAULT_RESTOCK_POLL_SECONDS: i32 = 5;
+const MIN_RESTOCK_POLL_SECONDS: i32 = 2;
+const MAX_RESTOCK_WATCH_SECONDS: i32 = 2 * 60 * 60;
+// How long until the event is fetched again when Kide rate limits without saying for how long
+const RATE_LIMITED_RETRY: Duration = Duration::from_secs(5);
+
+// What the account has left to spend in the task, None without a budget. The budget is the most
+// the account may spend on everything it reserves in a task, not on a single reservation.
+pub async fn remaining_budget(
+    task: &TaskKey,
+    account: &KideAccount,
+) -> Result<Option<i64>, DBError> {
+    if account.eligibility.budget.is_none() {
+        return Ok(None);
+    }
+
+    let spent = TaskResult::spent(task, account.uuid).await?;
+    Ok(account.eligibility.remaining_budget(spent))
+}
+
+// How far a run of a task got
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Progress {
+    Finished,
+    // More variants open later or Kide rate limited the run, the next run is scheduled for then
+    Continued(DateTime<Utc>),
+}
+
+// What the earlier rounds of a task did, carried over to the round scheduled for later variants
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct RoundState {
+    // Whether an earlier run saw the sale open, runs before that wait for it
+    #[serde(default)]
+    pub sale_opened: bool,
+    // Variants that had their round already
+    pub opened: HashSet<String>,
+    // Accounts that got something
+    pub reserved: HashSet<Uuid>,
+    pub round: i32,
+}
+
+impl Default for RoundState {
+    fn default() -> Self {
+        Self {
+            sale_opened: false,
+            opened: HashSet::new(),
+            reserved: HashSet::new(),
+            round: 1,
+        }
+    }
+}
+
+// Returns whether anything was reserved for the account
 async fn reserve_in_succession(
+    task: &TaskKey,
     sale_client: SaleClient,
     account: KideAccount,
     count: i64,
     priority_strategy: TicketPriorityStrategy,
-) -> Result<(), FangError> {
+    allocation: Option<Allocation>,
+) -> Result<bool, FangError> {
+    // Tokens are only ever decrypted here, right before they are needed
+    let token = account.decrypt_token()?;
+    let mut reserved_any = false;
+
     for i in 1..count + 1 {
-        if sale_client.sale.product.max_total_reservations_per_checkout > -1 {
+        let strategy = Count { count: i };
+
+        let result = if sale_client.sale.product.max_total_reservations_per_checkout > -1 {
             log::trace!("Global limit detected, reserving a single variant only...");
-            let _ = sale_client
-                .reserve_fuzzy(
-                    account.token.clone(),
-                    &Count { count: i },
-                    &priority_strategy,
-                )
-                .await;
+            let (chosen, ranking) = match &allocation {
+                Some(allocation) => (allocation.variant.clone(), allocation.ranking.clone()),
+                None => priority_strategy
+                    .choose_explained(&sale_client.sale.variants, Some(&account.eligibility)),
+            };
+
+            reserve_with_fallback(
+                task,
+                &sale_client,
+                &account,
+                &token,
+                &strategy,
+                chosen,
+                &ranking,
+            )
+            .await
         } else {
-            let _ = sale_client
-                .reserve_all(account.token.clone(), &Count { count: i })
-                .await;
+            reserve_all_verified(
+                task,
+                &sale_client,
+                &account,
+                &token,
+                &strategy,
+                &priority_strategy,
+            )
+            .await
+        };
+
+        match result {
+            Ok(Some(reserved)) => {
+                reserved_any = true;
+                TaskEvent::emit(
+                    task,
+                    TaskEventKind::ReservationSucceeded,
+                    format!("Reserved {} for {}", reserved, account.name),
+                    Some(account.uuid),
+                )
+                .await
+            }
+            Ok(None) => {
+                TaskEvent::emit(
+                    task,
+                    TaskEventKind::ReservationFailed,
+                    format!("Nothing to reserve for {}", account.name),
+                    Some(account.uuid),
+                )
+                .await
+            }
+            Err(e) => {
+                TaskEvent::emit(
+                    task,
+                    TaskEventKind::ReservationFailed,
+                    format!("Reservation for {} failed: {}", account.name, e),
+                    Some(account.uuid),
+                )
+                .await
+            }
         }
 
         log::debug!(
@@ -39,61 +165,752 @@ async fn reserve_in_succession(
         );
     }
 
-    Ok(())
+    Ok(reserved_any)
+}
+
+// Reserves the chosen variant and checks the basket for it. Whatever doesn't stick falls back to
+// the next acceptable variant of the ranking, up to MAX_FALLBACKS times. Returns the name of what
+// was reserved.
+async fn reserve_with_fallback(
+    task: &TaskKey,
+    sale_client: &SaleClient,
+    account: &KideAccount,
+    token: &Secret,
+    strategy: &Count,
+    chosen: Option<Variant>,
+    ranking: &[ScoreBreakdown],
+) -> Result<Option<String>, ReservationError> {
+    let chosen = match chosen {
+        Some(chosen) => chosen,
+        None => {
+            TaskResult::record(task, Some(account.uuid), None, ranking).await;
+            return Ok(None);
+        }
+    };
+
+    let fallbacks = ranking
+        .iter()
+        .filter(|breakdown| breakdown.excluded.is_none() && breakdown.variant_id != chosen.id)
+        .filter_map(|breakdown| {
+            sale_client
+                .sale
+                .variants
+                .iter()
+                .find(|variant| variant.id == breakdown.variant_id)
+                .cloned()
+        });
+    let budget = remaining_budget(task, account).await?;
+    let attempts: Vec<Variant> = std::iter::once(chosen)
+        .chain(fallbacks)
+        .take(MAX_FALLBACKS + 1)
+        .collect();
+
+    // What the account already holds, so verifying only counts what the task adds
+    let mut basket = sale_client.snapshot(token).await;
+    let mut last_error = None;
+    for variant in attempts {
+        TaskEvent::emit(
+            task,
+            TaskEventKind::VariantChosen,
+            format!("Chose {} for {}", variant.name, account.name),
+            Some(account.uuid),
+        )
+        .await;
+
+        let result_id = TaskResult::record(task, Some(account.uuid), Some(&variant), ranking).await;
+        let reservation = variant.to_reservation(strategy);
+
+        let response = match sale_client.reserve(&variant, token, strategy, budget).await {
+            Ok(response) => response,
+            Err(e) => {
+                TaskResult::reconcile(result_id, reservation.quantity, Some(0), None).await;
+                last_error = Some(e);
+                continue;
+            }
+        };
+        let response_expiry =
+            reserved_until(response.as_ref(), &reservation.inventory_id, Utc::now());
+
+        let before = match &basket {
+            Some(before) => before,
+            None => {
+                // Without the basket from before, there's no telling what the reservation added
+                TaskResult::reconcile(result_id, reservation.quantity, None, response_expiry).await;
+                return Ok(Some(variant.name));
+            }
+        };
+
+        match sale_client
+            .verify(token, before, &[reservation.clone()], response.as_ref())
+            .await
+        {
+            Ok((reconciled, after)) => {
+                basket = Some(after);
+                let granted = reconciled.iter().map(|r| r.granted).sum::<i64>();
+                let expires_at = reconciled.iter().filter_map(|r| r.expires_at).min();
+                TaskResult::reconcile(result_id, reservation.quantity, Some(granted), expires_at)
+                    .await;
+
+                if granted > 0 {
+                    if granted < reservation.quantity {
+                        log::warn!(
+                            "Only {} of {} {} granted for {}",
+                            granted,
+                            reservation.quantity,
+                            variant.name,
+                            account.name
+                        );
+                    }
+
+                    return Ok(Some(variant.name));
+                }
+
+                TaskEvent::emit(
+                    task,
+                    TaskEventKind::ReservationFailed,
+                    format!(
+                        "{} for {} was not in the basket, falling back",
+                        variant.name, account.name
+                    ),
+                    Some(account.uuid),
+                )
+                .await;
+            }
+            Err(e) => {
+                // The reservation itself went through, not being able to check it is no reason to
+                // reserve something else on top of it
+                log::warn!("Could not verify the basket of {}: {}", account.name, e);
+                TaskResult::reconcile(result_id, reservation.quantity, None, response_expiry).await;
+                return Ok(Some(variant.name));
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+// Reserves every acceptable variant and checks the basket for them. There is nothing to fall
+// back to, so shortfalls are only recorded.
+async fn reserve_all_verified(
+    task: &TaskKey,
+    sale_client: &SaleClient,
+    account: &KideAccount,
+    token: &Secret,
+    strategy: &Count,
+    priority_strategy: &TicketPriorityStrategy,
+) -> Result<Option<String>, ReservationError> {
+    let budget = remaining_budget(task, account).await?;
+    let before = sale_client.snapshot(token).await;
+    let (requested, response) = sale_client
+        .reserve_all(
+            token,
+            strategy,
+            priority_strategy,
+            &account.eligibility,
+            budget,
+        )
+        .await?;
+
+    if requested.is_empty() {
+        return Ok(None);
+    }
+
+    let reconciled = match &before {
+        Some(before) => match sale_client
+            .verify(token, before, &requested, response.as_ref())
+            .await
+        {
+            Ok((reconciled, _)) => Some(reconciled),
+            Err(e) => {
+                log::warn!("Could not verify the basket of {}: {}", account.name, e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut granted_variants = 0;
+    for reservation in &requested {
+        let variant = sale_client
+            .sale
+            .variants
+            .iter()
+            .find(|variant| variant.inventory_id == reservation.inventory_id);
+        let reconciliation = reconciled.as_ref().and_then(|reconciled| {
+            reconciled
+                .iter()
+                .find(|r| r.inventory_id == reservation.inventory_id)
+        });
+        let granted = reconciliation.map(|r| r.granted);
+
+        // Reservations that couldn't be checked are assumed to have gone through
+        if granted != Some(0) {
+            granted_variants += 1;
+        }
+
+        let expires_at = match reconciliation {
+            Some(reconciliation) => reconciliation.expires_at,
+            None => reserved_until(response.as_ref(), &reservation.inventory_id, Utc::now()),
+        };
+
+        let result_id = TaskResult::record(task, Some(account.uuid), variant, &[]).await;
+        TaskResult::reconcile(result_id, reservation.quantity, granted, expires_at).await;
+    }
+
+    if granted_variants == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "{} of {} variants",
+        granted_variants,
+        requested.len()
+    )))
+}
+
+// Waits on the shared poller until a variant that wasn't open before has opened, or gives up
+// after ROUND_POLL_TIMEOUT. Returns the latest state of the sale either way.
+async fn wait_for_variants(
+    event_id: &str,
+    mut sale_client: SaleClient,
+    opened: &HashSet<String>,
+) -> SaleClient {
+    let started = Instant::now();
+    let mut updates = get_poller().subscribe(event_id);
+
+    while let Some(remaining) = ROUND_POLL_TIMEOUT.checked_sub(started.elapsed()) {
+        match tokio::time::timeout(remaining, updates.next()).await {
+            Ok(Some(latest)) => sale_client = latest,
+            Ok(None) | Err(_) => break,
+        }
+
+        let now = Utc::now();
+        if sale_client
+            .sale
+            .variants
+            .iter()
+            .any(|variant| variant.sales_started(now) && !opened.contains(&variant.id))
+        {
+            break;
+        }
+    }
+
+    sale_client
+}
+
+// Reserves for every account at once. Returns the accounts that got something.
+async fn reserve_round(
+    task: &TaskKey,
+    round_client: &SaleClient,
+    accounts: Vec<KideAccount>,
+    priority_strategy: &TicketPriorityStrategy,
+    has_limit: bool,
+) -> Vec<Uuid> {
+    // Accounts reserving a single variant only go after the best one while it has stock left
+    // for them, the rest are sent to the next best instead of colliding on it
+    let mut allocations = if has_limit {
+        priority_strategy.allocate(&round_client.sale.variants, &accounts, &Count { count: 1 })
+    } else {
+        Vec::new()
+    };
+
+    let reserve_jobs = accounts.into_iter().map(|account| {
+        let account_uuid = account.uuid;
+        let allocation = allocations
+            .iter()
+            .position(|allocation| allocation.account == account_uuid)
+            .map(|index| allocations.swap_remove(index));
+
+        let job = reserve_in_succession(
+            task,
+            round_client.clone(),
+            account,
+            1,
+            priority_strategy.clone(),
+            allocation,
+        );
+        async move { (account_uuid, job.await) }
+    });
+
+    let mut reserved = Vec::new();
+    for (account_uuid, result) in join_all(reserve_jobs).await {
+        match result {
+            Ok(true) => reserved.push(account_uuid),
+            Ok(false) => {}
+            Err(e) => log::error!(
+                "Reserving for account {} failed: {}",
+                account_uuid,
+                e.description
+            ),
+        }
+    }
+
+    reserved
+}
+
+// Returned and expired reservations put stock back after the rush. Polls the sale for a while and
+// reserves for the accounts that got nothing whenever a variant's stock goes up.
+async fn watch_restocks(
+    task: &TaskKey,
+    client: &Client,
+    mut sale_client: SaleClient,
+    accounts: &[KideAccount],
+    reserved: &mut HashSet<Uuid>,
+    priority_strategy: &TicketPriorityStrategy,
+    mut queue: Option<&mut dyn AsyncQueueable>,
+) {
+    let options = &priority_strategy.options;
+    let watch = options
+        .restock_watch_seconds
+        .unwrap_or_default()
+        .clamp(0, MAX_RESTOCK_WATCH_SECONDS);
+    let interval = options
+        .restock_poll_seconds
+        .unwrap_or(DEFAULT_RESTOCK_POLL_SECONDS)
+        .max(MIN_RESTOCK_POLL_SECONDS);
+
+    let deadline = Instant::now() + Duration::from_secs(watch as u64);
+    let interval = Duration::from_secs(interval as u64);
+    let has_limit = sale_client.sale.product.max_total_reservations_per_checkout > -1;
+
+    TaskEvent::emit(
+        task,
+        TaskEventKind::Waiting,
+        format!("Watching for restocks for {} seconds", watch),
+        None,
+    )
+    .await;
+
+    let mut stock: HashMap<String, i64> = HashMap::new();
+    for variant in &sale_client.sale.variants {
+        stock.insert(variant.id.clone(), variant.availability);
+    }
+
+    while Instant::now() + interval < deadline
+        && Utc::now() < sale_client.sale.product.date_sales_until
+    {
+        let waiting: Vec<KideAccount> = accounts
+            .iter()
+            .filter(|account| !reserved.contains(&account.uuid))
+            .cloned()
+            .collect();
+        if waiting.is_empty() {
+            break;
+        }
+
+        tokio::time::sleep(interval).await;
+
+        match client.product(task.event_id.clone()).await {
+            Ok(latest) => sale_client = latest,
+            Err(RequestError::RateLimited { retry_after }) => {
+                log::warn!("Rate limited while watching {} for restocks", task.event_id);
+                tokio::time::sleep(retry_after.unwrap_or(interval)).await;
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Failed to refresh event {}: {}", task.event_id, e);
+                continue;
+            }
+        }
+
+        let now = Utc::now();
+        let restocked: Vec<Variant> = sale_client
+            .sale
+            .variants
+            .iter()
+            .filter(|variant| variant.sales_started(now))
+            .filter(|variant| {
+                variant.availability > 0
+                    && variant.availability > stock.get(&variant.id).copied().unwrap_or(0)
+            })
+            .cloned()
+            .collect();
+        for variant in &sale_client.sale.variants {
+            stock.insert(variant.id.clone(), variant.availability);
+        }
+
+        // Only worth a try if one of them is something the task would reserve at all
+        if restocked
+            .iter()
+            .all(|variant| priority_strategy.explain(variant, None).excluded.is_some())
+        {
+            continue;
+        }
+
+        TaskEvent::emit(
+            task,
+            TaskEventKind::Restocked,
+            format!("{} variants back in stock", restocked.len()),
+            None,
+        )
+        .await;
+
+        // With a checkout limit the best of everything is picked again, otherwise only what
+        // came back is reserved
+        let mut round_client = sale_client.clone();
+        round_client.sale.variants = if has_limit {
+            sale_client.sale.open_variants(now)
+        } else {
+            restocked
+        };
+
+        reserved.extend(
+            reserve_round(task, &round_client, waiting, priority_strategy, has_limit).await,
+        );
+
+        if let Some(queue) = queue.as_deref_mut() {
+            schedule_hold_reminders(queue, task).await;
+        }
+    }
 }
 
 pub async fn scalp(
-    event_id: String,
+    task: TaskKey,
     account_ids: AccountIDList,
     options: TaskOptions,
-) -> Result<(), FangError> {
-    let priority_strategy = TicketPriorityStrategy::new(options);
-
+    queue: Option<&mut dyn AsyncQueueable>,
+) -> Result<Progress, FangError> {
     // Fetch the accounts from the database
     log::debug!("Fetching accounts...");
     let accounts = KideAccount::from_uuids(account_ids).await?;
 
-    // Initialize the connection to the kide api
-    log::debug!("Initializing client...");
-    let client = Client::new();
-    let mut sale_client = client.product(event_id.clone()).await.unwrap();
+    run(&task, &accounts, options, RoundState::default(), queue).await
+}
+
+// Picks up a task where its earlier run left off, see `reserve_rounds` and `fetch_event`
+pub async fn resume(
+    task: TaskKey,
+    account_ids: AccountIDList,
+    options: TaskOptions,
+    state: RoundState,
+    queue: Option<&mut dyn AsyncQueueable>,
+) -> Result<Progress, FangError> {
+    let accounts = KideAccount::from_uuids(account_ids).await?;
+
+    run(&task, &accounts, options, state, queue).await
+}
+
+async fn run(
+    task: &TaskKey,
+    accounts: &[KideAccount],
+    options: TaskOptions,
+    mut state: RoundState,
+    mut queue: Option<&mut dyn AsyncQueueable>,
+) -> Result<Progress, FangError> {
+    let mut sale_client = match fetch_event(task, accounts, &options, &state, &mut queue).await? {
+        Fetched::Event(sale_client) => sale_client,
+        Fetched::Rescheduled(at) => return Ok(Progress::Continued(at)),
+    };
+
+    if state.sale_opened {
+        // Scheduled right at the start of the variants, they may take a moment to show up as open
+        sale_client = wait_for_variants(&task.event_id, sale_client, &state.opened).await;
+    } else {
+        sale_client = wait_for_sale(task, sale_client).await?;
+        state.sale_opened = true;
+    }
+
+    let priority_strategy =
+        TicketPriorityStrategy::new(options).with_categories(&sale_client.sale.categories);
+
+    if let Some(group_size) = priority_strategy.options.group_size {
+        let deadline = priority_strategy
+            .options
+            .group_deadline_seconds
+            .unwrap_or(DEFAULT_GROUP_DEADLINE_SECONDS);
+
+        log::info!("Booking a group of {}...", group_size);
+        book_group(
+            task,
+            get_client(),
+            sale_client,
+            accounts.to_vec(),
+            &priority_strategy,
+            group_size as i64,
+            Duration::from_secs(deadline.max(0) as u64),
+        )
+        .await?;
+
+        if let Some(queue) = queue {
+            schedule_hold_reminders(queue, task).await;
+        }
+
+        return Ok(Progress::Finished);
+    }
+
+    reserve_rounds(
+        task,
+        sale_client,
+        accounts,
+        &priority_strategy,
+        state,
+        queue,
+    )
+    .await
+}
+
+// What fetching the event at the start of a run got
+enum Fetched {
+    Event(SaleClient),
+    // Kide rate limited the fetch, the run is scheduled again for then
+    Rescheduled(DateTime<Utc>),
+}
+
+// Fetches the event at the start of a run. When Kide rate limits the fetch, the run is scheduled
+// again for when Kide allows it. Without a queue to schedule it on, the run waits for it instead.
+async fn fetch_event(
+    task: &TaskKey,
+    accounts: &[KideAccount],
+    options: &TaskOptions,
+    state: &RoundState,
+    queue: &mut Option<&mut dyn AsyncQueueable>,
+) -> Result<Fetched, FangError> {
+    loop {
+        let retry_after = match get_client().product(task.event_id.clone()).await {
+            Ok(sale_client) => return Ok(Fetched::Event(sale_client)),
+            Err(RequestError::RateLimited { retry_after }) => {
+                retry_after.unwrap_or(RATE_LIMITED_RETRY)
+            }
+            Err(e) => {
+                return Err(FangError {
+                    description: format!("Failed to fetch event {}: {}", task.event_id, e),
+                })
+            }
+        };
+
+        let retry_at = Utc::now() + chrono::Duration::from_std(retry_after).unwrap_or_default();
+        TaskEvent::emit(
+            task,
+            TaskEventKind::Waiting,
+            format!("Rate limited by Kide, trying again at {}", retry_at),
+            None,
+        )
+        .await;
+
+        if let Some(queue) = queue.as_deref_mut() {
+            let retry = VariantRoundTask::new(
+                task.clone(),
+                accounts.iter().map(|account| account.uuid).collect(),
+                options.clone(),
+                retry_at,
+                state.clone(),
+            );
+            queue
+                .schedule_task(&retry as &dyn AsyncRunnable)
+                .await
+                .map_err(|e| FangError {
+                    description: format!("Failed to schedule the next run: {:?}", e),
+                })?;
+
+            return Ok(Fetched::Rescheduled(retry_at));
+        }
+
+        tokio::time::sleep(retry_after).await;
+    }
+}
 
-    // Block until the sale starts.
-    // If there's over 2 seconds left until the sale starts, sleep for 1 second and
-    // recheck.
-    // If there's less than 2 seconds left until the sale starts, sleep for only 0.1 seconds.
+// Blocks until the sale starts. The event is polled by the worker's shared poller, every second
+// until shortly before the sale starts and then every 100 ms, whichever task on the worker is
+// waiting on it.
+async fn wait_for_sale(
+    task: &TaskKey,
+    mut sale_client: SaleClient,
+) -> Result<SaleClient, FangError> {
     if sale_client.sale.variants.len() == 0 {
         log::debug!("Waiting for sale to start...");
+        TaskEvent::emit(
+            task,
+            TaskEventKind::Waiting,
+            format!(
+                "Sale starts at {}",
+                sale_client.sale.product.date_sales_from
+            ),
+            None,
+        )
+        .await;
+
+        let poller = get_poller();
+        let mut updates = poller.subscribe(&task.event_id);
+        let mut polling = false;
         loop {
-            let now = Utc::now();
-            let diff = sale_client.sale.product.date_sales_from - now;
-            log::debug!("{} seconds until sale starts", diff.num_seconds());
-            if diff.num_seconds() > 2 {
-                tokio::time::sleep(Duration::from_millis(1000)).await;
-            } else {
-                tokio::time::sleep(Duration::from_millis(100)).await;
+            let starts_at = sale_client.sale.product.date_sales_from;
+            log::debug!(
+                "{} seconds until sale starts",
+                (starts_at - Utc::now()).num_seconds()
+            );
+            if !polling && poller.schedule().is_near(starts_at) {
+                polling = true;
+                TaskEvent::emit(task, TaskEventKind::Polling, "Polling for variants", None).await;
             }
 
-            sale_client = client.product(event_id.clone()).await.unwrap();
+            sale_client = match updates.next().await {
+                Some(latest) => latest,
+                None => {
+                    return Err(FangError {
+                        description: format!("Stopped polling event {}", task.event_id),
+                    })
+                }
+            };
             if sale_client.sale.variants.len() > 0 {
                 break;
             }
         }
     }
 
-    // Begin reserving tickets
-    log::info!("Reserving all variants...");
-    log::trace!("Using following info: {:?}", sale_client.sale);
-    let measurement_begin = Instant::now();
+    TaskEvent::emit(
+        task,
+        TaskEventKind::SaleOpened,
+        format!("{} variants available", sale_client.sale.variants.len()),
+        None,
+    )
+    .await;
 
-    let reserve_jobs = accounts.into_iter().map(|account| {
-        reserve_in_succession(sale_client.clone(), account, 1, priority_strategy.clone())
-    });
-    join_all(reserve_jobs).await;
+    Ok(sale_client)
+}
+
+// Variants can open later than the product. Every round reserves what opened since the previous
+// one. When more variants open later, a round is scheduled for their start rather than keeping
+// the worker waiting. Without a queue to schedule it on, the round waits for them.
+async fn reserve_rounds(
+    task: &TaskKey,
+    mut sale_client: SaleClient,
+    accounts: &[KideAccount],
+    priority_strategy: &TicketPriorityStrategy,
+    mut state: RoundState,
+    mut queue: Option<&mut dyn AsyncQueueable>,
+) -> Result<Progress, FangError> {
+    let has_limit = sale_client.sale.product.max_total_reservations_per_checkout > -1;
+
+    loop {
+        let now = Utc::now();
+        let newly_opened: Vec<Variant> = sale_client
+            .sale
+            .open_variants(now)
+            .into_iter()
+            .filter(|variant| !state.opened.contains(&variant.id))
+            .collect();
+        state
+            .opened
+            .extend(newly_opened.iter().map(|variant| variant.id.clone()));
+
+        if !newly_opened.is_empty() {
+            if state.round > 1 {
+                TaskEvent::emit(
+                    task,
+                    TaskEventKind::SaleOpened,
+                    format!("{} more variants opened", newly_opened.len()),
+                    None,
+                )
+                .await;
+            }
+
+            // With a checkout limit an account reserves a single variant, the best of everything
+            // open so far, so accounts that already got one are done. Without a limit accounts
+            // reserve everything, so only what just opened.
+            let mut round_client = sale_client.clone();
+            round_client.sale.variants = if has_limit {
+                sale_client.sale.open_variants(now)
+            } else {
+                newly_opened
+            };
+
+            // Begin reserving tickets
+            log::info!("Reserving variants, round {}...", state.round);
+            log::trace!("Using following info: {:?}", round_client.sale);
+            let measurement_begin = Instant::now();
+
+            let round_accounts: Vec<KideAccount> = accounts
+                .iter()
+                .filter(|account| !has_limit || !state.reserved.contains(&account.uuid))
+                .cloned()
+                .collect();
+
+            state.reserved.extend(
+                reserve_round(
+                    task,
+                    &round_client,
+                    round_accounts,
+                    priority_strategy,
+                    has_limit,
+                )
+                .await,
+            );
+
+            let execution_time = measurement_begin.elapsed().as_millis();
+            log::debug!("Round {} took {}ms", state.round, execution_time);
+            state.round += 1;
+
+            if let Some(queue) = queue.as_deref_mut() {
+                schedule_hold_reminders(queue, task).await;
+            }
+        }
+
+        // Variants that only open after the sale has ended are never waited for
+        let sales_until = sale_client.sale.product.date_sales_until;
+        let next_start: Option<DateTime<Utc>> = sale_client
+            .sale
+            .variants
+            .iter()
+            .filter(|variant| !state.opened.contains(&variant.id))
+            .map(|variant| variant.date_sales_from)
+            .filter(|start| *start < sales_until)
+            .min();
+
+        let next_start = match next_start {
+            Some(next_start) => next_start,
+            None => break,
+        };
+
+        TaskEvent::emit(
+            task,
+            TaskEventKind::Waiting,
+            format!("More variants open at {}", next_start),
+            None,
+        )
+        .await;
+
+        if let Some(queue) = queue.as_deref_mut() {
+            let next_round = VariantRoundTask::new(
+                task.clone(),
+                accounts.iter().map(|account| account.uuid).collect(),
+                priority_strategy.options.clone(),
+                next_start,
+                state,
+            );
+            queue
+                .schedule_task(&next_round as &dyn AsyncRunnable)
+                .await
+                .map_err(|e| FangError {
+                    description: format!("Failed to schedule the next round: {:?}", e),
+                })?;
+
+            return Ok(Progress::Continued(next_start));
+        }
+
+        let delay = (next_start - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(delay).await;
+
+        sale_client = wait_for_variants(&task.event_id, sale_client, &state.opened).await;
+    }
+
+    if priority_strategy.options.restock_watch_seconds.is_some() {
+        log::info!("Watching for restocks...");
+        watch_restocks(
+            task,
+            get_client(),
+            sale_client,
+            accounts,
+            &mut state.reserved,
+            priority_strategy,
+            queue,
+        )
+        .await;
+    }
 
-    let execution_time = measurement_begin.elapsed().as_millis();
-    log::debug!("Execution took {}ms", execution_time);
     log::info!("Done");
 
-    Ok(())
+    Ok(Progress::Finished)
 }
@@ -0,0 +1,88 @@
+use juniper::{GraphQLScalar, InputValue, ScalarValue, Value};
+use std::fmt;
+
+const MASK: &str = "********";
+
+// Wraps credentials so they can't end up in logs or API responses by accident. The only way to
+// get the value back is `expose`.
+#[derive(Clone, PartialEq, GraphQLScalar)]
+#[graphql(
+    description = "A write-only secret, it is never returned by the API",
+    to_output_with = Self::to_output,
+    from_input_with = Self::from_input,
+    parse_token(String)
+)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    // Last few characters, enough to tell tokens apart without revealing them
+    pub fn preview(&self) -> String {
+        let chars: Vec<char> = self.0.chars().collect();
+        if chars.len() < 16 {
+            return MASK.to_string();
+        }
+
+        let tail: String = chars[chars.len() - 4..].iter().collect();
+        format!("{}{}", MASK, tail)
+    }
+
+    fn to_output<S: ScalarValue>(&self) -> Value<S> {
+        Value::scalar(MASK.to_string())
+    }
+
+    fn from_input<S: ScalarValue>(value: &InputValue<S>) -> Result<Self, String> {
+        value
+            .as_string_value()
+            .map(|value| Self(value.to_string()))
+            .ok_or_else(|| format!("Expected `String`, found: {}", value))
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", MASK)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_formats_the_value() {
+        let secret = Secret::new("kide-token-1234567890".to_string());
+
+        assert_eq!(format!("{}", secret), MASK);
+        assert_eq!(format!("{:?}", secret), format!("Secret({})", MASK));
+        assert!(!format!("{:?}", Some(&secret)).contains("kide-token"));
+    }
+
+    #[test]
+    fn previews_only_the_tail_of_long_values() {
+        assert_eq!(
+            Secret::new("kide-token-1234567890".to_string()).preview(),
+            format!("{}7890", MASK)
+        );
+        assert_eq!(Secret::new("short".to_string()).preview(), MASK);
+    }
+}
@@ -1,8 +1,14 @@
-use crate::api::Variant;
+use crate::account::{Eligibility, KideAccount};
+use crate::api::{format_cents, Category, Variant};
 use crate::task::TaskOptions;
+use juniper::GraphQLObject;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::collections::HashMap;
 use std::cmp::Ordering;
 use sublime_fuzzy::best_match;
+use uuid::Uuid;
 
 const NEGATIVE_WORDS: [&str; 3] = [
     "allergia",
@@ -52,11 +58,58 @@ impl Quantity for All {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+#[graphql(description = "How much a single keyword contributed to a variant's name score")]
+pub struct KeywordScore {
+    pub keyword: String,
+    // Negative for negative keywords, already multiplied by their penalty
+    pub score: i32,
+}
+
+// Everything that went into ranking a variant, kept with task results so a choice can be
+// explained after the sale
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+#[graphql(description = "Why a variant was ranked the way it was")]
+pub struct ScoreBreakdown {
+    pub variant_id: String,
+    pub variant_name: String,
+    pub keywords: Vec<KeywordScore>,
+    // Weighted components, `total` is their sum
+    pub name_score: i32,
+    pub price_score: i32,
+    pub total: i32,
+    // Why `choose` would never pick this variant, the first of `exclusions`
+    pub excluded: Option<String>,
+    // Every filter the variant fails. Rankings recorded before this was kept only have `excluded`.
+    #[serde(default)]
+    pub exclusions: Vec<String>,
+}
+
+// A variant with its score, as ranked by `TicketPriorityStrategy::rank`
+#[derive(Debug, Clone)]
+pub struct RankedVariant {
+    pub variant: Variant,
+    pub breakdown: ScoreBreakdown,
+}
+
+// The variant `TicketPriorityStrategy::allocate` assigned to an account, with the account's
+// ranking it was picked from
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub account: Uuid,
+    pub variant: Option<Variant>,
+    pub ranking: Vec<ScoreBreakdown>,
+}
+
 #[derive(Debug, Clone)]
 pub struct TicketPriorityStrategy {
     pub name_weight: i32,
     pub price_weight: i32,
     pub options: TaskOptions,
+    // Categories of the event the variants belong to, see `with_categories`
+    pub categories: Vec<Category>,
 }
 
 impl TicketPriorityStrategy {
@@ -65,23 +118,261 @@ impl TicketPriorityStrategy {
             name_weight: 1,
             price_weight: 1000,
             options,
+            categories: Vec::new(),
         }
     }
 
+    // Variants don't say which category they are in, so category filters are checked against
+    // the categories of the event. Without them, included categories never match.
+    pub fn with_categories(mut self, categories: &[Category]) -> Self {
+        self.categories = categories.to_vec();
+        self
+    }
+
+    // Chooses without regard to what the account can buy, prefer `choose_explained` with the
+    // account's eligibility when reserving for an account
     pub fn choose(&self, variants: &Vec<Variant>) -> Option<Variant> {
-        let mut variants = variants.clone();
+        self.choose_explained(variants, None).0
+    }
+
+    // Same as `choose`, but also returns the breakdown of every variant in ranking order.
+    // Variants the account isn't eligible for are excluded.
+    pub fn choose_explained(
+        &self,
+        variants: &[Variant],
+        eligibility: Option<&Eligibility>,
+    ) -> (Option<Variant>, Vec<ScoreBreakdown>) {
+        let ranked = self.rank(variants, eligibility);
+
+        let chosen = ranked
+            .iter()
+            .find(|ranked| ranked.breakdown.excluded.is_none())
+            .map(|ranked| ranked.variant.clone());
+        let breakdowns = ranked.into_iter().map(|ranked| ranked.breakdown).collect();
+
+        (chosen, breakdowns)
+    }
+
+    // Assigns every account a variant. Accounts are assigned in order, each getting its best
+    // variant that still has stock left for it, so the best variant is filled up to its
+    // availability before accounts spill over to the next one. An account is counted for what
+    // `strategy` asks for, capped at what it may reserve of the variant.
+    pub fn allocate(
+        &self,
+        variants: &[Variant],
+        accounts: &[KideAccount],
+        strategy: &impl Quantity,
+    ) -> Vec<Allocation> {
+        // Quantity allocated per inventory id so far
+        let mut allocated: HashMap<String, i64> = HashMap::new();
+        let mut allocations = Vec::new();
+
+        for account in accounts {
+            let ranked = self.rank(variants, Some(&account.eligibility));
+
+            let chosen = ranked
+                .iter()
+                .filter(|ranked| ranked.breakdown.excluded.is_none())
+                .find_map(|ranked| {
+                    let variant = &ranked.variant;
+                    let taken = allocated.get(&variant.inventory_id).copied().unwrap_or(0);
+                    let quantity = cmp::min(strategy.quantity(variant), All.quantity(variant));
+
+                    (quantity > 0 && variant.availability - taken >= quantity)
+                        .then(|| (variant.clone(), quantity))
+                });
+
+            let variant = chosen.map(|(variant, quantity)| {
+                log::trace!(
+                    "Allocated {} of {} to account {}",
+                    quantity,
+                    variant.name,
+                    account.name
+                );
+                *allocated.entry(variant.inventory_id.clone()).or_default() += quantity;
+                variant
+            });
+
+            allocations.push(Allocation {
+                account: account.uuid,
+                variant,
+                ranking: ranked.into_iter().map(|ranked| ranked.breakdown).collect(),
+            });
+        }
+
+        allocations
+    }
+
+    // Every variant in the order `choose` would consider them, excluded variants last
+    pub fn rank(
+        &self,
+        variants: &[Variant],
+        eligibility: Option<&Eligibility>,
+    ) -> Vec<RankedVariant> {
+        let mut ranked: Vec<RankedVariant> = variants
+            .iter()
+            .map(|variant| RankedVariant {
+                variant: variant.clone(),
+                breakdown: self.explain(variant, eligibility),
+            })
+            .collect();
+
+        // Stable, so equally scored variants keep the order the API returned them in
+        ranked.sort_by(|a, b| {
+            a.breakdown
+                .excluded
+                .is_some()
+                .cmp(&b.breakdown.excluded.is_some())
+                .then(b.breakdown.total.cmp(&a.breakdown.total))
+        });
+
+        ranked
+    }
+
+    pub fn explain(
+        &self,
+        variant: &Variant,
+        eligibility: Option<&Eligibility>,
+    ) -> ScoreBreakdown {
+        let keywords = Self::score_keywords(&variant.name);
+        let name_score = Self::sum_keywords(&keywords) * self.name_weight;
+        let price_score = self.calculate_price_score(variant.price_per_item) * self.price_weight;
+
+        let exclusions = self.exclusion_reasons(variant, eligibility);
+
+        ScoreBreakdown {
+            variant_id: variant.id.clone(),
+            variant_name: variant.name.clone(),
+            keywords,
+            name_score,
+            price_score,
+            total: name_score + price_score,
+            excluded: exclusions.first().cloned(),
+            exclusions,
+        }
+    }
+
+    // Every filter the variant fails, not just the first, so a ranking shows everything that
+    // would have to change for the variant to be picked
+    fn exclusion_reasons(
+        &self,
+        variant: &Variant,
+        eligibility: Option<&Eligibility>,
+    ) -> Vec<String> {
+        let mut reasons = Vec::new();
 
         // Filter out variants that are sold out
-        variants.retain(|variant| variant.availability > 0);
+        if variant.availability <= 0 {
+            reasons.push("Sold out".to_string());
+        }
 
-        if !self.options.ignore_membership {
-            // Filter out variants that require membership
-            variants.retain(|variant| !variant.is_product_variant_membership_required);
+        // Filter out variants of unwanted categories and product types
+        reasons.extend(self.category_exclusion(variant));
+        let product_type = variant.product_type;
+        let is_product_type = |filter: &i32| i64::from(*filter) == product_type;
+        if !self.options.include_product_types.is_empty()
+            && !self
+                .options
+                .include_product_types
+                .iter()
+                .any(is_product_type)
+        {
+            reasons.push(format!("Product type {} is not included", product_type));
+        }
+        if self
+            .options
+            .exclude_product_types
+            .iter()
+            .any(is_product_type)
+        {
+            reasons.push(format!("Product type {} is excluded", product_type));
+        }
+
+        // Filter out variants outside of the price range
+        if let Some(min_price) = self.options.min_price {
+            if variant.price_per_item < min_price as i64 {
+                reasons.push(format!(
+                    "Costs {}, below the minimum price of {}",
+                    format_cents(variant.price_per_item),
+                    format_cents(min_price as i64)
+                ));
+            }
+        }
+        if let Some(max_price) = self.options.max_price {
+            if variant.price_per_item > max_price as i64 {
+                reasons.push(format!(
+                    "Costs {}, above the maximum price of {}",
+                    format_cents(variant.price_per_item),
+                    format_cents(max_price as i64)
+                ));
+            }
         }
 
-        variants.sort_by(|a, b| self.compare_variants(a.clone(), b.clone()));
+        // Filter out variants the account can't buy
+        if let Some(reason) =
+            eligibility.and_then(|eligibility| eligibility.ineligibility_reason(variant))
+        {
+            reasons.push(reason);
+        }
+
+        reasons
+    }
 
-        variants.first().cloned()
+    // The categories the variant is in. Kide doesn't list categories per variant, only for the
+    // event, so every variant is in the categories of its event.
+    fn variant_categories(&self, _variant: &Variant) -> &[Category] {
+        &self.categories
+    }
+
+    fn category_exclusion(&self, variant: &Variant) -> Option<String> {
+        let categories = self.variant_categories(variant);
+        let matches = |filter: &String| {
+            categories.iter().any(|category| {
+                category.id == *filter || category.name.eq_ignore_ascii_case(filter)
+            })
+        };
+
+        if !self.options.include_categories.is_empty()
+            && !self.options.include_categories.iter().any(matches)
+        {
+            return Some("Event is not in an included category".to_string());
+        }
+
+        let excluded = self
+            .options
+            .exclude_categories
+            .iter()
+            .find(|filter| matches(*filter));
+        if let Some(excluded) = excluded {
+            return Some(format!("Event is in the excluded category {}", excluded));
+        }
+
+        None
+    }
+
+    // Checks whether a variant name satisfies `target_name`, either as a regex or as a fuzzy
+    // match. Without a target name every variant matches.
+    pub fn matches_name(&self, name: &str) -> Result<bool, regex::Error> {
+        let target_name = match &self.options.target_name {
+            Some(target_name) => target_name,
+            None => return Ok(true),
+        };
+
+        if self.options.use_regex {
+            Ok(Regex::new(target_name)?.is_match(name))
+        } else {
+            Ok(best_match(target_name, name).is_some())
+        }
+    }
+
+    // Checks the price against the price range and the target price, without either every
+    // price matches
+    pub fn matches_price(&self, price: i64) -> bool {
+        let above_min = self.options.min_price.filter(|min| price < *min as i64).is_none();
+        let below_max = self.options.max_price.filter(|max| price > *max as i64).is_none();
+        let in_range = above_min && below_max;
+
+        in_range && (self.options.target_price.is_none() || self.calculate_price_score(price) > 0)
     }
 
     pub fn compare_variants(&self, a: Variant, b: Variant) -> cmp::Ordering {
@@ -108,52 +399,45 @@ impl TicketPriorityStrategy {
     }
 
     fn calculate_score(&self, variant: &Variant) -> i32 {
-        let name_score = self.calculate_name_score(&variant.name);
-        let price_score = self.calculate_price_score(variant.price_per_item);
-
-        name_score * self.name_weight + price_score * self.price_weight
+        self.explain(variant, None).total
     }
 
-    // Helper function to calculate the name score (fuzzy string comparison)
-    fn calculate_name_score(&self, name: &str) -> i32 {
-        Self::score_word(name).try_into().unwrap_or(0)
+    fn sum_keywords(keywords: &[KeywordScore]) -> i32 {
+        keywords.iter().map(|keyword| keyword.score).sum()
     }
 
-    fn score_word(word: &str) -> isize {
-        let mut positive_score = 0;
-        let mut negative_score = 0;
+    // Name score (fuzzy string comparison) of every keyword that matched the name at all,
+    // negative ones weigh ten times as much
+    fn score_keywords(word: &str) -> Vec<KeywordScore> {
+        let mut keywords = Vec::new();
 
         for positive_word in POSITIVE_WORDS.iter() {
-            let score = match best_match(positive_word, word) {
-                Some(m) => m.score(),
-                None => 0,
-            };
-
-            positive_score += score;
+            if let Some(m) = best_match(positive_word, word) {
+                keywords.push(KeywordScore {
+                    keyword: positive_word.to_string(),
+                    score: m.score().try_into().unwrap_or(0),
+                });
+            }
         }
 
         for negative_word in NEGATIVE_WORDS.iter() {
-            let score = match best_match(negative_word, word) {
-                Some(m) => m.score(),
-                None => 0,
-            };
-
-            negative_score += score;
+            if let Some(m) = best_match(negative_word, word) {
+                let score: i32 = m.score().try_into().unwrap_or(0);
+                keywords.push(KeywordScore {
+                    keyword: negative_word.to_string(),
+                    score: score.saturating_mul(-10),
+                });
+            }
         }
 
-        positive_score - negative_score * 10
+        keywords
     }
 
     // Helper function to calculate the price score (exact price match)
     fn calculate_price_score(&self, price: i64) -> i32 {
         match self.options.target_price {
             Some(target_price) => {
-                let target_price = target_price * 100;
-                log::trace!(
-                    "Comparing price {} to target price {}",
-                    price,
-                    target_price as i64
-                );
+                log::trace!("Comparing price {} to target price {}", price, target_price);
                 if price == target_price as i64 {
                     100
                 } else {
@@ -164,3 +448,121 @@ impl TicketPriorityStrategy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(id: &str, availability: i64) -> Variant {
+        Variant {
+            id: id.to_string(),
+            inventory_id: id.to_string(),
+            name: id.to_string(),
+            availability,
+            product_variant_maximum_reservable_quantity: 10,
+            product_variant_maximum_item_quantity_per_user: 2,
+            ..Default::default()
+        }
+    }
+
+    fn accounts(count: usize) -> Vec<KideAccount> {
+        (0..count)
+            .map(|_| KideAccount {
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn allocated(allocations: &[Allocation]) -> Vec<Option<String>> {
+        allocations
+            .iter()
+            .map(|allocation| {
+                allocation
+                    .variant
+                    .as_ref()
+                    .map(|variant| variant.id.clone())
+            })
+            .collect()
+    }
+
+    // Equally scored, so they rank in this order
+    fn variants(best: i64, next: i64) -> Vec<Variant> {
+        vec![variant("best", best), variant("next", next)]
+    }
+
+    #[test]
+    fn excludes_variants_by_the_categories_of_the_event() {
+        let strategy = TicketPriorityStrategy::new(TaskOptions {
+            exclude_categories: vec!["sitsit".to_string()],
+            ..Default::default()
+        })
+        .with_categories(&[Category {
+            id: "1".to_string(),
+            name: "Sitsit".to_string(),
+            ..Default::default()
+        }]);
+
+        let reasons = strategy.exclusion_reasons(&variant("best", 1), None);
+
+        assert_eq!(reasons, vec!["Event is in the excluded category sitsit"]);
+    }
+
+    #[test]
+    fn compares_product_types_without_truncating() {
+        let strategy = TicketPriorityStrategy::new(TaskOptions {
+            include_product_types: vec![1],
+            ..Default::default()
+        });
+        let variant = Variant {
+            product_type: (1 << 32) + 1,
+            ..variant("best", 1)
+        };
+
+        assert_eq!(
+            strategy.exclusion_reasons(&variant, None),
+            vec![format!("Product type {} is not included", (1i64 << 32) + 1)]
+        );
+    }
+
+    #[test]
+    fn allocates_the_best_variant_while_it_has_stock() {
+        let strategy = TicketPriorityStrategy::new(TaskOptions::default());
+        let allocations = strategy.allocate(&variants(3, 3), &accounts(3), &Count { count: 1 });
+
+        assert_eq!(allocated(&allocations), vec![Some("best".to_string()); 3]);
+    }
+
+    #[test]
+    fn spills_over_to_the_next_variant_when_the_best_runs_out() {
+        let strategy = TicketPriorityStrategy::new(TaskOptions::default());
+        let allocations = strategy.allocate(&variants(2, 1), &accounts(4), &Count { count: 1 });
+
+        assert_eq!(
+            allocated(&allocations),
+            vec![
+                Some("best".to_string()),
+                Some("best".to_string()),
+                Some("next".to_string()),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_accounts_for_what_they_may_reserve() {
+        let strategy = TicketPriorityStrategy::new(TaskOptions::default());
+
+        // Two each, capped by the per user maximum
+        let allocations = strategy.allocate(&variants(4, 10), &accounts(3), &Count { count: 5 });
+
+        assert_eq!(
+            allocated(&allocations),
+            vec![
+                Some("best".to_string()),
+                Some("best".to_string()),
+                Some("next".to_string()),
+            ]
+        );
+    }
+}
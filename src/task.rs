@@ -1,5 +1,7 @@
 use crate::account::AccountIDList;
-use crate::scalp::scalp;
+use crate::db::{get_db_manager, DBError};
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::scalp::{resume, scalp, Progress, RoundState};
 
 use chrono::{DateTime, Utc};
 use fang::async_trait;
@@ -14,6 +16,24 @@ use tokio::time::Duration;
 use tokio_postgres::Row;
 use uuid::Uuid;
 
+// Every owner can have a task for the same event, the events and results of a task are recorded
+// under its event and owner so they are never mixed up with another owner's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskKey {
+    pub event_id: String,
+    // None for the tasks without an owner, which only admins see
+    pub owner_id: Option<Uuid>,
+}
+
+impl TaskKey {
+    pub fn new(event_id: impl Into<String>, owner_id: Option<Uuid>) -> Self {
+        Self {
+            event_id: event_id.into(),
+            owner_id,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "fang::serde")]
 #[serde(rename_all = "camelCase")]
@@ -23,6 +43,9 @@ pub struct ScalpingTask {
     pub account_ids: Vec<Uuid>,
     pub sale_start: DateTime<Utc>,
     pub options: TaskOptions,
+    // Tasks scheduled before users existed have no owner and are only visible to admins
+    #[serde(default)]
+    pub owner_id: Option<Uuid>,
 }
 
 impl ScalpingTask {
@@ -31,14 +54,38 @@ impl ScalpingTask {
         account_ids: AccountIDList,
         sale_start: DateTime<Utc>,
         options: TaskOptions,
+        owner_id: Option<Uuid>,
     ) -> Self {
         Self {
             event_id,
             account_ids,
             sale_start,
             options,
+            owner_id,
         }
     }
+
+    pub fn key(&self) -> TaskKey {
+        TaskKey::new(self.event_id.clone(), self.owner_id)
+    }
+
+    // Whether the owner already has an unfinished task for the event, `None` being the tasks
+    // without an owner. Finished and failed tasks don't stop the event from being scalped again.
+    pub async fn exists(event_id: &str, owner_id: Option<Uuid>) -> Result<bool, DBError> {
+        let db = get_db_manager();
+        let owner_id = owner_id.map(|owner_id| owner_id.to_string());
+        let row = db
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM fang_tasks \
+                 WHERE metadata->>'type' = 'ScalpingTask' AND metadata->>'eventId' = $1 \
+                 AND metadata->>'ownerId' IS NOT DISTINCT FROM $2 \
+                 AND state IN ('new', 'in_progress', 'retried'))",
+                &[&event_id, &owner_id],
+            )
+            .await?;
+
+        Ok(row.get(0))
+    }
 }
 
 impl<'a> TryFrom<&'a Row> for ScalpingTask {
@@ -51,40 +98,94 @@ impl<'a> TryFrom<&'a Row> for ScalpingTask {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, GraphQLObject)]
+// Tasks scheduled before eligibility was tracked per account still carry `ignoreMembership`,
+// unknown fields are ignored so they keep loading
+#[derive(Debug, Clone, Default, Serialize, Deserialize, GraphQLObject)]
 #[serde(crate = "fang::serde")]
 #[serde(rename_all = "camelCase")]
 #[graphql(description = "Options for a task")]
 pub struct TaskOptions {
+    // Preferred price, in cents like the price range
     pub target_price: Option<i32>,
+    // Variants outside of the range are never reserved, in cents
+    #[serde(default)]
+    pub min_price: Option<i32>,
+    #[serde(default)]
+    pub max_price: Option<i32>,
+    // Category ids or names, the event has to be in one of the included categories and in none
+    // of the excluded ones. Kide only lists categories for the whole event, not per variant, so
+    // these decide whether the task reserves anything at all.
+    #[serde(default)]
+    #[graphql(
+        description = "Event categories, by id or name. The event has to be in one of them, \
+                       otherwise nothing is reserved. Variants have no categories of their own."
+    )]
+    pub include_categories: Vec<String>,
+    #[serde(default)]
+    #[graphql(
+        description = "Event categories, by id or name. Nothing is reserved if the event is in \
+                       any of them. Variants have no categories of their own."
+    )]
+    pub exclude_categories: Vec<String>,
+    // Kide product types of the variants, e.g. to skip add-on products
+    #[serde(default)]
+    pub include_product_types: Vec<i32>,
+    #[serde(default)]
+    pub exclude_product_types: Vec<i32>,
+    // Reserve this many tickets across the accounts or none at all
+    #[serde(default)]
+    pub group_size: Option<i32>,
+    // How long after the sale opens the group has to be complete, in seconds
+    #[serde(default)]
+    pub group_deadline_seconds: Option<i32>,
+    // Keep watching this long after the sale for returned and expired tickets, in seconds
+    #[serde(default)]
+    pub restock_watch_seconds: Option<i32>,
+    // How often to check for restocks while watching, in seconds
+    #[serde(default)]
+    pub restock_poll_seconds: Option<i32>,
     pub target_name: Option<String>,
     pub use_regex: bool,
-    pub ignore_membership: bool,
 }
 
-impl Default for TaskOptions {
-    fn default() -> Self {
-        Self {
-            target_price: None,
-            target_name: None,
-            use_regex: false,
-            ignore_membership: true,
+// The task ends once the last of its rounds is done, a run that scheduled another round is just a
+// step along the way
+async fn report(task: &TaskKey, result: Result<Progress, FangError>) -> Result<(), FangError> {
+    match &result {
+        Ok(Progress::Finished) => {
+            TaskEvent::emit(task, TaskEventKind::Finished, "Task finished", None).await
+        }
+        Ok(Progress::Continued(_)) => {}
+        Err(e) => {
+            TaskEvent::emit(
+                task,
+                TaskEventKind::Failed,
+                format!("Task failed: {}", e.description),
+                None,
+            )
+            .await
         }
     }
+
+    result.map(|_| ())
 }
 
 #[async_trait]
 #[typetag::serde]
 impl AsyncRunnable for ScalpingTask {
-    async fn run(&self, _queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
-        scalp(
-            self.event_id.clone(),
+    async fn run(&self, queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+        let key = self.key();
+        TaskEvent::emit(&key, TaskEventKind::Started, "Task started", None).await;
+
+        let result = scalp(
+            key.clone(),
             self.account_ids.clone(),
             self.options.clone(),
+            Some(queue),
         )
-        .await?;
+        .await;
 
-        Ok(())
+        report(&key, result).await
     }
 
     fn cron(&self) -> Option<Scheduled> {
@@ -105,3 +206,77 @@ impl AsyncRunnable for ScalpingTask {
         "common".to_string()
     }
 }
+
+// Reserves the variants of a task that open later than the sale. Scheduled by the task at their
+// start, so no worker is kept waiting for them. Also picks up a run Kide rate limited, once Kide
+// allows it again.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct VariantRoundTask {
+    pub event_id: String,
+    pub account_ids: Vec<Uuid>,
+    pub options: TaskOptions,
+    pub owner_id: Option<Uuid>,
+    pub starts_at: DateTime<Utc>,
+    pub state: RoundState,
+}
+
+impl VariantRoundTask {
+    pub fn new(
+        task: TaskKey,
+        account_ids: AccountIDList,
+        options: TaskOptions,
+        starts_at: DateTime<Utc>,
+        state: RoundState,
+    ) -> Self {
+        Self {
+            event_id: task.event_id,
+            account_ids,
+            options,
+            owner_id: task.owner_id,
+            starts_at,
+            state,
+        }
+    }
+
+    pub fn key(&self) -> TaskKey {
+        TaskKey::new(self.event_id.clone(), self.owner_id)
+    }
+}
+
+#[async_trait]
+#[typetag::serde]
+impl AsyncRunnable for VariantRoundTask {
+    async fn run(&self, queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+        let key = self.key();
+
+        let result = resume(
+            key.clone(),
+            self.account_ids.clone(),
+            self.options.clone(),
+            self.state.clone(),
+            Some(queue),
+        )
+        .await;
+
+        report(&key, result).await
+    }
+
+    fn cron(&self) -> Option<Scheduled> {
+        Some(Scheduled::ScheduleOnce(self.starts_at))
+    }
+
+    fn uniq(&self) -> bool {
+        true
+    }
+
+    // A retry reserves the round again, once is enough
+    fn max_retries(&self) -> i32 {
+        1
+    }
+
+    fn task_type(&self) -> String {
+        "common".to_string()
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::{DateTime, Utc};
+use juniper::{GraphQLEnum, GraphQLObject};
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::db::{get_db_manager, DBError};
+
+#[derive(Debug, Clone, Copy, PartialEq, GraphQLEnum)]
+#[graphql(description = "What a user is allowed to see")]
+pub enum Role {
+    // Sees and manages everything
+    Admin,
+    // Sees and manages only what they own
+    User,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::User => "user",
+        }
+    }
+
+    fn from_db(role: &str) -> Self {
+        match role {
+            "admin" => Role::Admin,
+            _ => Role::User,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, GraphQLObject)]
+#[graphql(description = "A user of the API, owning accounts and tasks")]
+pub struct User {
+    pub uuid: Uuid,
+    pub name: String,
+    pub role: Role,
+    // Task outcomes and token warnings are mailed here
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'a> TryFrom<&'a Row> for User {
+    type Error = DBError;
+
+    fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        let role: String = row.try_get("role")?;
+
+        Ok(Self {
+            uuid: row.try_get("uuid")?,
+            name: row.try_get("name")?,
+            role: Role::from_db(&role),
+            email: row.try_get("email")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    // The owner to filter queries by, admins aren't limited to anything
+    pub fn scope(&self) -> Option<Uuid> {
+        if self.is_admin() {
+            None
+        } else {
+            Some(self.uuid)
+        }
+    }
+
+    pub fn can_access(&self, owner: Option<Uuid>) -> bool {
+        self.is_admin() || owner == Some(self.uuid)
+    }
+
+    pub async fn create(name: String, role: Role) -> Result<Self, DBError> {
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_one(
+                "INSERT INTO users (name, role) VALUES ($1, $2) RETURNING *",
+                &[&name, &role.as_str()],
+            )
+            .await?;
+
+        Self::try_from(&row)
+    }
+
+    pub async fn set_email(&mut self, email: Option<String>) -> Result<(), DBError> {
+        let db_manager = get_db_manager();
+        db_manager
+            .execute(
+                "UPDATE users SET email = $1 WHERE uuid = $2",
+                &[&email, &self.uuid],
+            )
+            .await?;
+
+        self.email = email;
+        Ok(())
+    }
+
+    pub async fn from_uuid(uuid: Uuid) -> Result<Option<Self>, DBError> {
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_opt("SELECT * FROM users WHERE uuid = $1", &[&uuid])
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(Self::try_from(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn all() -> Result<Vec<Self>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query("SELECT * FROM users ORDER BY created_at", &[])
+            .await?;
+
+        let mut users = Vec::new();
+        for row in rows {
+            users.push(Self::try_from(&row)?);
+        }
+
+        Ok(users)
+    }
+}
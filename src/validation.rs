@@ -0,0 +1,680 @@
+use chrono::Utc;
+use juniper::{GraphQLEnum, GraphQLObject};
+use uuid::Uuid;
+
+use crate::account::{AccountIDList, KideAccount};
+use crate::db::DBError;
+use crate::request::Client;
+use crate::sale::Sale;
+use crate::strategy::TicketPriorityStrategy;
+use crate::task::TaskOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, GraphQLEnum)]
+#[graphql(description = "The kind of problem found while validating a task")]
+pub enum IssueKind {
+    EventNotFound,
+    EventEnded,
+    NoAccounts,
+    AccountNotFound,
+    TokenExpired,
+    TokenUnreadable,
+    HakaRequired,
+    NotEligible,
+    VariantsNotPublished,
+    NoMatchingVariant,
+    InvalidRegex,
+    InvalidGroupSize,
+}
+
+#[derive(Debug, Clone, GraphQLObject)]
+#[graphql(description = "A single problem found while validating a task")]
+pub struct ValidationIssue {
+    pub kind: IssueKind,
+    pub message: String,
+    pub account: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Default, GraphQLObject)]
+#[graphql(description = "Result of validating a task before it is scheduled")]
+pub struct ValidationReport {
+    pub errors: Vec<ValidationIssue>,
+    pub warnings: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, kind: IssueKind, message: String, account: Option<Uuid>) {
+        self.errors.push(ValidationIssue {
+            kind,
+            message,
+            account,
+        });
+    }
+
+    fn warn(&mut self, kind: IssueKind, message: String, account: Option<Uuid>) {
+        self.warnings.push(ValidationIssue {
+            kind,
+            message,
+            account,
+        });
+    }
+}
+
+// The report together with the fetched event, so callers don't need to fetch it again
+pub struct Preflight {
+    pub report: ValidationReport,
+    pub sale: Option<Sale>,
+}
+
+pub async fn validate_task(
+    client: &Client,
+    event_id: &str,
+    account_ids: &AccountIDList,
+    options: &TaskOptions,
+) -> Result<Preflight, DBError> {
+    let mut report = ValidationReport::default();
+
+    let sale = match client.product(event_id.to_string()).await {
+        Ok(sale_client) => Some(sale_client.sale),
+        Err(e) => {
+            report.error(
+                IssueKind::EventNotFound,
+                format!("Could not fetch event {}: {}", event_id, e),
+                None,
+            );
+            None
+        }
+    };
+
+    if let Some(sale) = &sale {
+        check_event(&mut report, sale);
+    }
+
+    let accounts = check_accounts(&mut report, account_ids, sale.as_ref()).await?;
+
+    check_group_size(&mut report, options, accounts.len(), sale.as_ref());
+
+    if let Some(sale) = &sale {
+        check_variants(&mut report, sale, options, &accounts);
+    }
+
+    Ok(Preflight { report, sale })
+}
+
+fn check_group_size(
+    report: &mut ValidationReport,
+    options: &TaskOptions,
+    accounts: usize,
+    sale: Option<&Sale>,
+) {
+    if let Some(group_size) = options.group_size {
+        if group_size < 1 {
+            report.error(
+                IssueKind::InvalidGroupSize,
+                format!("Group size has to be at least 1, got {}", group_size),
+                None,
+            );
+        } else if (group_size as usize) < accounts {
+            report.warn(
+                IssueKind::InvalidGroupSize,
+                format!(
+                    "Group of {} has more accounts ({}) than tickets, some won't reserve anything",
+                    group_size, accounts
+                ),
+                None,
+            );
+        }
+
+        // A group is booked once, right when the sale opens. It isn't booked again for restocks
+        // or for variants opening later.
+        if options.restock_watch_seconds.is_some() {
+            report.error(
+                IssueKind::InvalidGroupSize,
+                "Groups can't watch for restocks, drop one of the options".to_string(),
+                None,
+            );
+        }
+
+        let later = sale.and_then(|sale| {
+            sale.variants.iter().find(|variant| {
+                variant.date_sales_from > sale.product.date_sales_from
+                    && variant.date_sales_from < sale.product.date_sales_until
+            })
+        });
+        if let Some(variant) = later {
+            report.error(
+                IssueKind::InvalidGroupSize,
+                format!(
+                    "{} opens at {}, after the sale, groups can't wait for it",
+                    variant.name, variant.date_sales_from
+                ),
+                None,
+            );
+        }
+    }
+}
+
+fn check_event(report: &mut ValidationReport, sale: &Sale) {
+    let product = &sale.product;
+
+    if product.sales_ended || product.date_sales_until < Utc::now() {
+        report.error(
+            IssueKind::EventEnded,
+            format!(
+                "Sales for {} ended at {}",
+                product.name, product.date_sales_until
+            ),
+            None,
+        );
+    }
+}
+
+// Returns the accounts that exist
+async fn check_accounts(
+    report: &mut ValidationReport,
+    account_ids: &AccountIDList,
+    sale: Option<&Sale>,
+) -> Result<Vec<KideAccount>, DBError> {
+    let mut accounts = Vec::new();
+
+    if account_ids.is_empty() {
+        report.error(
+            IssueKind::NoAccounts,
+            "Task has no accounts".to_string(),
+            None,
+        );
+    }
+
+    for account_id in account_ids {
+        let account = match KideAccount::from_uuid(*account_id).await? {
+            Some(account) => account,
+            None => {
+                report.error(
+                    IssueKind::AccountNotFound,
+                    format!("Kide account {} does not exist", account_id),
+                    Some(*account_id),
+                );
+                continue;
+            }
+        };
+
+        if account.token.is_none() {
+            report.warn(
+                IssueKind::TokenUnreadable,
+                format!(
+                    "Token of {} is not encrypted yet, a worker encrypts it when it starts",
+                    account.name
+                ),
+                Some(account.uuid),
+            );
+        }
+
+        match account.token_expires_at {
+            Some(expires_at) if expires_at < Utc::now() => report.error(
+                IssueKind::TokenExpired,
+                format!("Token of {} expired at {}", account.name, expires_at),
+                Some(account.uuid),
+            ),
+            Some(expires_at) => {
+                if let Some(sale) = sale {
+                    if expires_at < sale.product.date_sales_from {
+                        report.warn(
+                            IssueKind::TokenExpired,
+                            format!(
+                                "Token of {} expires at {}, before the sale starts",
+                                account.name, expires_at
+                            ),
+                            Some(account.uuid),
+                        );
+                    }
+                }
+            }
+            None => report.warn(
+                IssueKind::TokenUnreadable,
+                format!("Could not read the expiry of {}'s token", account.name),
+                Some(account.uuid),
+            ),
+        }
+
+        if let Some(sale) = sale {
+            check_haka(report, sale, &account);
+        }
+
+        accounts.push(account);
+    }
+
+    Ok(accounts)
+}
+
+// Kide refuses every reservation of an event requiring HAKA from an account without it
+fn check_haka(report: &mut ValidationReport, sale: &Sale, account: &KideAccount) {
+    if sale.is_haka_required && !account.eligibility.has_haka {
+        report.error(
+            IssueKind::HakaRequired,
+            format!(
+                "Event requires HAKA authentication, but {} has none linked",
+                account.name
+            ),
+            Some(account.uuid),
+        );
+    }
+}
+
+fn check_variants(
+    report: &mut ValidationReport,
+    sale: &Sale,
+    options: &TaskOptions,
+    accounts: &[KideAccount],
+) {
+    // Variants are usually only published once the sale starts
+    if sale.variants.is_empty() {
+        report.warn(
+            IssueKind::VariantsNotPublished,
+            "Variants are not published yet, options can't be checked".to_string(),
+            None,
+        );
+        return;
+    }
+
+    let strategy = TicketPriorityStrategy::new(options.clone()).with_categories(&sale.categories);
+
+    let ranking = strategy.rank(&sale.variants, None);
+    if let Some(first) = ranking.first() {
+        if ranking
+            .iter()
+            .all(|ranked| ranked.breakdown.excluded.is_some())
+        {
+            report.error(
+                IssueKind::NoMatchingVariant,
+                format!(
+                    "Every variant is excluded by the task's options, e.g. {}: {}",
+                    first.variant.name,
+                    first.breakdown.excluded.clone().unwrap_or_default()
+                ),
+                None,
+            );
+        }
+    }
+
+    let candidates: Vec<_> = sale.variants.iter().collect();
+
+    // Accounts are never sent to reserve variants they can't buy, so an account that can't buy
+    // anything is useless for this task
+    for account in accounts {
+        let eligible = candidates
+            .iter()
+            .any(|variant| account.eligibility.ineligibility_reason(variant).is_none());
+
+        if !eligible {
+            // Every reason once, an account can fall short differently for different variants
+            let mut reasons: Vec<String> = Vec::new();
+            for variant in &candidates {
+                if let Some(reason) = account.eligibility.ineligibility_reason(variant) {
+                    if !reasons.contains(&reason) {
+                        reasons.push(reason);
+                    }
+                }
+            }
+            let reason = reasons.join(", ");
+
+            report.error(
+                IssueKind::NotEligible,
+                format!(
+                    "{} is not eligible for any variant ({})",
+                    account.name, reason
+                ),
+                Some(account.uuid),
+            );
+        }
+    }
+
+    // A report without warnings means some variant matches the name and the price at once, not
+    // just one variant the name and another the price
+    let mut name_matches = 0;
+    let mut price_matches = 0;
+    let mut full_matches = 0;
+    for variant in &candidates {
+        let name_match = match strategy.matches_name(&variant.name) {
+            Ok(matches) => matches,
+            Err(e) => {
+                report.error(
+                    IssueKind::InvalidRegex,
+                    format!("Invalid target name: {}", e),
+                    None,
+                );
+                return;
+            }
+        };
+        let price_match = strategy.matches_price(variant.price_per_item);
+
+        if name_match {
+            name_matches += 1;
+        }
+        if price_match {
+            price_matches += 1;
+        }
+        if name_match && price_match {
+            full_matches += 1;
+        }
+    }
+
+    if name_matches == 0 {
+        report.warn(
+            IssueKind::NoMatchingVariant,
+            "No variant matches the target name".to_string(),
+            None,
+        );
+    }
+
+    if price_matches == 0 {
+        report.warn(
+            IssueKind::NoMatchingVariant,
+            "No variant matches the target price or price range".to_string(),
+            None,
+        );
+    }
+
+    if name_matches > 0 && price_matches > 0 && full_matches == 0 {
+        report.warn(
+            IssueKind::NoMatchingVariant,
+            "No single variant matches both the target name and the target price".to_string(),
+            None,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Product, Variant};
+    use chrono::Duration;
+
+    fn variant(name: &str, price: i64) -> Variant {
+        Variant {
+            id: name.to_lowercase(),
+            name: name.to_string(),
+            inventory_id: format!("{}-inventory", name.to_lowercase()),
+            price_per_item: price,
+            availability: 10,
+            ..Default::default()
+        }
+    }
+
+    fn sale(variants: Vec<Variant>) -> Sale {
+        Sale {
+            product: Product {
+                name: "Sitsit".to_string(),
+                date_sales_until: Utc::now() + Duration::days(1),
+                ..Default::default()
+            },
+            variants,
+            ..Default::default()
+        }
+    }
+
+    fn options(target_name: Option<&str>, min_price: Option<i32>) -> TaskOptions {
+        TaskOptions {
+            target_name: target_name.map(str::to_string),
+            use_regex: true,
+            min_price,
+            ..Default::default()
+        }
+    }
+
+    fn kinds(issues: &[ValidationIssue]) -> Vec<IssueKind> {
+        issues.iter().map(|issue| issue.kind).collect()
+    }
+
+    #[test]
+    fn passes_when_a_variant_matches_name_and_price() {
+        let sale = sale(vec![variant("Sitsit", 2500), variant("Afterparty", 1000)]);
+        let mut report = ValidationReport::default();
+
+        check_variants(
+            &mut report,
+            &sale,
+            &options(Some("^Sitsit$"), Some(2000)),
+            &[],
+        );
+
+        assert!(report.is_ok());
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn warns_when_name_and_price_only_match_different_variants() {
+        let sale = sale(vec![variant("Sitsit", 1000), variant("Afterparty", 2500)]);
+        let mut report = ValidationReport::default();
+
+        check_variants(
+            &mut report,
+            &sale,
+            &options(Some("^Sitsit$"), Some(2000)),
+            &[],
+        );
+
+        assert!(report.is_ok());
+        assert_eq!(kinds(&report.warnings), vec![IssueKind::NoMatchingVariant]);
+        assert!(report.warnings[0].message.contains("both"));
+    }
+
+    #[test]
+    fn warns_about_name_and_price_separately() {
+        let sale = sale(vec![variant("Afterparty", 2500)]);
+        let mut report = ValidationReport::default();
+
+        check_variants(
+            &mut report,
+            &sale,
+            &TaskOptions {
+                target_price: Some(1000),
+                ..options(Some("^Sitsit$"), None)
+            },
+            &[],
+        );
+
+        assert_eq!(
+            kinds(&report.warnings),
+            vec![IssueKind::NoMatchingVariant, IssueKind::NoMatchingVariant]
+        );
+    }
+
+    #[test]
+    fn errors_when_every_variant_is_excluded() {
+        let sale = sale(vec![variant("Sitsit", 2500)]);
+        let mut report = ValidationReport::default();
+
+        check_variants(
+            &mut report,
+            &sale,
+            &TaskOptions {
+                max_price: Some(1000),
+                ..Default::default()
+            },
+            &[],
+        );
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::NoMatchingVariant]);
+    }
+
+    #[test]
+    fn passes_when_variants_open_later() {
+        let sale = sale(vec![Variant {
+            date_sales_from: Utc::now() + Duration::hours(1),
+            ..variant("Sitsit", 2500)
+        }]);
+        let mut report = ValidationReport::default();
+
+        check_variants(&mut report, &sale, &options(Some("^Sitsit$"), None), &[]);
+
+        assert!(report.is_ok());
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn errors_on_invalid_regex() {
+        let sale = sale(vec![variant("Sitsit", 2500)]);
+        let mut report = ValidationReport::default();
+
+        check_variants(&mut report, &sale, &options(Some("(unclosed"), None), &[]);
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::InvalidRegex]);
+    }
+
+    #[test]
+    fn warns_when_variants_are_not_published() {
+        let sale = sale(vec![]);
+        let mut report = ValidationReport::default();
+
+        check_variants(&mut report, &sale, &options(Some("^Sitsit$"), None), &[]);
+
+        assert!(report.is_ok());
+        assert_eq!(
+            kinds(&report.warnings),
+            vec![IssueKind::VariantsNotPublished]
+        );
+    }
+
+    #[test]
+    fn errors_for_accounts_that_cant_buy_anything() {
+        let sale = sale(vec![Variant {
+            is_product_variant_student_card_required: true,
+            ..variant("Sitsit", 2500)
+        }]);
+        let account = KideAccount {
+            uuid: Uuid::new_v4(),
+            name: "Matti".to_string(),
+            ..Default::default()
+        };
+        let mut report = ValidationReport::default();
+
+        check_variants(
+            &mut report,
+            &sale,
+            &TaskOptions::default(),
+            &[account.clone()],
+        );
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::NotEligible]);
+        assert_eq!(report.errors[0].account, Some(account.uuid));
+    }
+
+    #[test]
+    fn gives_every_reason_an_account_cant_buy_anything() {
+        let sale = sale(vec![
+            Variant {
+                is_product_variant_student_card_required: true,
+                ..variant("Sitsit", 2500)
+            },
+            Variant {
+                is_product_variant_haka_authentication_required: true,
+                ..variant("Afterparty", 1000)
+            },
+        ]);
+        let account = KideAccount {
+            uuid: Uuid::new_v4(),
+            name: "Matti".to_string(),
+            ..Default::default()
+        };
+        let mut report = ValidationReport::default();
+
+        check_variants(&mut report, &sale, &TaskOptions::default(), &[account]);
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::NotEligible]);
+        assert!(report.errors[0].message.contains("student card"));
+        assert!(report.errors[0].message.contains("HAKA"));
+    }
+
+    #[test]
+    fn errors_for_accounts_without_haka_on_haka_events() {
+        let sale = Sale {
+            is_haka_required: true,
+            ..sale(vec![])
+        };
+        let account = KideAccount {
+            uuid: Uuid::new_v4(),
+            name: "Matti".to_string(),
+            ..Default::default()
+        };
+        let mut report = ValidationReport::default();
+
+        check_haka(&mut report, &sale, &account);
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::HakaRequired]);
+    }
+
+    #[test]
+    fn errors_for_groups_watching_restocks() {
+        let options = TaskOptions {
+            group_size: Some(2),
+            restock_watch_seconds: Some(60),
+            ..Default::default()
+        };
+        let mut report = ValidationReport::default();
+
+        check_group_size(&mut report, &options, 2, None);
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::InvalidGroupSize]);
+    }
+
+    #[test]
+    fn errors_for_groups_with_variants_opening_later() {
+        let sale = sale(vec![
+            variant("Sitsit", 2500),
+            Variant {
+                date_sales_from: Utc::now() + Duration::hours(1),
+                ..variant("Afterparty", 1000)
+            },
+        ]);
+        let options = TaskOptions {
+            group_size: Some(2),
+            ..Default::default()
+        };
+        let mut report = ValidationReport::default();
+
+        check_group_size(&mut report, &options, 2, Some(&sale));
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::InvalidGroupSize]);
+        assert!(report.errors[0].message.contains("Afterparty"));
+    }
+
+    #[test]
+    fn errors_when_the_sale_has_ended() {
+        let mut ended = sale(vec![]);
+        ended.product.date_sales_until = Utc::now() - Duration::hours(1);
+        let mut report = ValidationReport::default();
+
+        check_event(&mut report, &ended);
+
+        assert_eq!(kinds(&report.errors), vec![IssueKind::EventEnded]);
+    }
+
+    #[test]
+    fn checks_the_group_size() {
+        let mut report = ValidationReport::default();
+        check_group_size(
+            &mut report,
+            &TaskOptions {
+                group_size: Some(0),
+                ..Default::default()
+            },
+            1,
+        );
+        assert_eq!(kinds(&report.errors), vec![IssueKind::InvalidGroupSize]);
+
+        let mut report = ValidationReport::default();
+        check_group_size(
+            &mut report,
+            &TaskOptions {
+                group_size: Some(2),
+                ..Default::default()
+            },
+            3,
+        );
+        assert!(report.is_ok());
+        assert_eq!(kinds(&report.warnings), vec![IssueKind::InvalidGroupSize]);
+    }
+}
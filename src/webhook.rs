@@ -0,0 +1,644 @@
+use chrono::{DateTime, Utc};
+use fang::async_trait;
+use fang::asynk::async_queue::AsyncQueueable;
+use fang::serde::{Deserialize, Serialize};
+use fang::typetag;
+use fang::AsyncRunnable;
+use fang::{FangError, ToFangError};
+use juniper::GraphQLObject;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::time::Duration;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::chat::{is_outcome, render, TaskSummary, WebhookFormat};
+use crate::crypto::{get_keyring, CryptoError, SealedToken};
+use crate::db::{get_db_manager, DBError};
+use crate::event::{TaskEvent, TaskEventKind};
+use crate::queue::get_dispatch_queue;
+use crate::secret::Secret;
+use crate::task::TaskKey;
+
+pub const SIGNATURE_HEADER: &str = "X-Crystal-Signature";
+pub const EVENT_HEADER: &str = "X-Crystal-Event";
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What a webhook without an event filter is sent
+pub const DEFAULT_EVENTS: [TaskEventKind; 6] = [
+    TaskEventKind::Scheduled,
+    TaskEventKind::Started,
+    TaskEventKind::ReservationSucceeded,
+    TaskEventKind::ReservationFailed,
+    TaskEventKind::Finished,
+    TaskEventKind::Failed,
+];
+
+#[derive(thiserror::Error, Debug, ToFangError)]
+pub enum WebhookError {
+    #[error("Database error")]
+    DBError(#[from] DBError),
+
+    #[error("Secret encryption error")]
+    CryptoError(#[from] CryptoError),
+
+    #[error("Could not sign payload")]
+    OpenSSL(#[from] ErrorStack),
+
+    #[error("Could not serialize payload")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("Delivery failed: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, GraphQLObject)]
+#[graphql(description = "An url task events are posted to")]
+pub struct Webhook {
+    pub uuid: Uuid,
+    pub owner_uuid: Option<Uuid>,
+    pub url: String,
+    // Used to sign the payloads, never handed out again
+    #[graphql(ignore)]
+    pub secret: SealedToken,
+    #[graphql(description = "Kinds of events sent, the task lifecycle if empty")]
+    pub events: Vec<TaskEventKind>,
+    pub format: WebhookFormat,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'a> TryFrom<&'a Row> for Webhook {
+    type Error = DBError;
+
+    fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        let events: Vec<String> = row.try_get("events")?;
+        let format: String = row.try_get("format")?;
+
+        Ok(Self {
+            uuid: row.try_get("uuid")?,
+            owner_uuid: row.try_get("owner_uuid")?,
+            url: row.try_get("url")?,
+            secret: SealedToken {
+                key_id: row.try_get("secret_key_id")?,
+                wrapped_key: row.try_get("secret_wrapped_key")?,
+                ciphertext: row.try_get("secret_ciphertext")?,
+            },
+            // Kinds this version doesn't know about are never emitted by it either
+            events: events
+                .iter()
+                .filter_map(|kind| TaskEventKind::from_db(kind))
+                .collect(),
+            format: WebhookFormat::from_db(&format).unwrap_or(WebhookFormat::Raw),
+            enabled: row.try_get("enabled")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+impl Webhook {
+    pub async fn create(
+        owner_uuid: Option<Uuid>,
+        url: String,
+        secret: &Secret,
+        events: &[TaskEventKind],
+        format: WebhookFormat,
+    ) -> Result<Self, WebhookError> {
+        let secret = SealedToken::seal(get_keyring(), secret.expose())?;
+        let events: Vec<&str> = events.iter().map(|kind| kind.as_str()).collect();
+
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_one(
+                "INSERT INTO webhooks (owner_uuid, url, secret_key_id, secret_wrapped_key, \
+                 secret_ciphertext, events, format) VALUES ($1, $2, $3, $4, $5, $6, $7) \
+                 RETURNING *",
+                &[
+                    &owner_uuid,
+                    &url,
+                    &secret.key_id,
+                    &secret.wrapped_key,
+                    &secret.ciphertext,
+                    &events,
+                    &format.as_str(),
+                ],
+            )
+            .await?;
+
+        Ok(Self::try_from(&row)?)
+    }
+
+    pub async fn save(&self) -> Result<(), DBError> {
+        let events: Vec<&str> = self.events.iter().map(|kind| kind.as_str()).collect();
+
+        let db_manager = get_db_manager();
+        db_manager
+            .execute(
+                "UPDATE webhooks SET url = $1, events = $2, format = $3, enabled = $4 \
+                 WHERE uuid = $5",
+                &[
+                    &self.url,
+                    &events,
+                    &self.format.as_str(),
+                    &self.enabled,
+                    &self.uuid,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(uuid: Uuid) -> Result<(), DBError> {
+        let db_manager = get_db_manager();
+        db_manager
+            .execute("DELETE FROM webhooks WHERE uuid = $1", &[&uuid])
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn from_uuid(uuid: Uuid) -> Result<Option<Webhook>, DBError> {
+        let db_manager = get_db_manager();
+        let row = db_manager
+            .query_opt("SELECT * FROM webhooks WHERE uuid = $1", &[&uuid])
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(Self::try_from(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    // All webhooks, or only the ones owned by `owner` if given
+    pub async fn all(owner: Option<Uuid>) -> Result<Vec<Webhook>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM webhooks WHERE $1::uuid IS NULL OR owner_uuid = $1 \
+                 ORDER BY created_at",
+                &[&owner],
+            )
+            .await?;
+
+        let mut webhooks = Vec::new();
+        for row in rows {
+            webhooks.push(Webhook::try_from(&row)?);
+        }
+
+        Ok(webhooks)
+    }
+
+    // Enabled webhooks allowed to see the events of a task. Webhooks without an owner see every
+    // task, the others only the tasks of their owner.
+    async fn for_task(task: &TaskKey) -> Result<Vec<Webhook>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM webhooks WHERE enabled AND (owner_uuid IS NULL OR owner_uuid = $1)",
+                &[&task.owner_id],
+            )
+            .await?;
+
+        let mut webhooks = Vec::new();
+        for row in rows {
+            webhooks.push(Webhook::try_from(&row)?);
+        }
+
+        Ok(webhooks)
+    }
+
+    // Rewraps every secret that isn't using the current key of the keyring
+    pub async fn rotate_secret_keys() -> Result<u64, WebhookError> {
+        let keyring = get_keyring();
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM webhooks WHERE secret_key_id <> $1",
+                &[&keyring.current_id()],
+            )
+            .await?;
+
+        let mut rotated = 0;
+        for row in rows {
+            let webhook = Webhook::try_from(&row)?;
+            let secret = webhook.secret.rewrap(keyring)?;
+
+            db_manager
+                .execute(
+                    "UPDATE webhooks SET secret_key_id = $1, secret_wrapped_key = $2, \
+                     secret_ciphertext = $3 WHERE uuid = $4",
+                    &[
+                        &secret.key_id,
+                        &secret.wrapped_key,
+                        &secret.ciphertext,
+                        &webhook.uuid,
+                    ],
+                )
+                .await?;
+
+            rotated += 1;
+        }
+
+        Ok(rotated)
+    }
+
+    pub fn wants(&self, kind: TaskEventKind) -> bool {
+        if self.events.is_empty() {
+            DEFAULT_EVENTS.contains(&kind)
+        } else {
+            self.events.contains(&kind)
+        }
+    }
+}
+
+// Hex encoded HMAC-SHA256 of the body, keyed with the webhook's secret
+pub fn sign_payload(secret: &[u8], body: &[u8]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body)?;
+
+    Ok(signer
+        .sign_to_vec()?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    // Id of the task event, receivers can use it to drop duplicate deliveries
+    pub id: i64,
+    pub event_id: String,
+    pub kind: String,
+    pub message: String,
+    pub account: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&TaskEvent> for WebhookPayload {
+    fn from(event: &TaskEvent) -> Self {
+        Self {
+            id: event.id,
+            event_id: event.event_id.clone(),
+            kind: event.kind.as_str().to_string(),
+            message: event.message.clone(),
+            account: event.account,
+            created_at: event.created_at,
+        }
+    }
+}
+
+// Queues a delivery of the event to every webhook that wants it. Like recording the event
+// itself, this never fails the task.
+pub async fn dispatch(event: &TaskEvent) {
+    let mut queue = match get_dispatch_queue() {
+        Some(queue) => queue,
+        None => return,
+    };
+
+    let webhooks = match Webhook::for_task(&event.key()).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            log::warn!("Failed to fetch webhooks for {}: {}", event.event_id, e);
+            return;
+        }
+    };
+
+    for webhook in webhooks.iter().filter(|webhook| webhook.wants(event.kind)) {
+        let task = WebhookDeliveryTask {
+            webhook: webhook.uuid,
+            payload: WebhookPayload::from(event),
+            owner_id: event.owner_id,
+        };
+
+        if let Err(e) = queue.insert_task(&task as &dyn AsyncRunnable).await {
+            log::warn!("Failed to queue delivery to {}: {:?}", webhook.uuid, e);
+        }
+    }
+}
+
+// Posts a payload to a webhook. Failed deliveries are retried by the queue.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "fang::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDeliveryTask {
+    pub webhook: Uuid,
+    pub payload: WebhookPayload,
+    // Owner of the task that emitted the event, for the summary of its outcome
+    #[serde(default)]
+    pub owner_id: Option<Uuid>,
+}
+
+impl WebhookDeliveryTask {
+    pub fn new(webhook: Uuid, payload: WebhookPayload) -> Self {
+        Self {
+            webhook,
+            payload,
+            owner_id: None,
+        }
+    }
+
+    async fn deliver(&self, webhook: &Webhook) -> Result<u16, WebhookError> {
+        // Chat messages about the end of a task describe everything it got
+        let summary = match webhook.format {
+            WebhookFormat::Raw => None,
+            _ if is_outcome(&self.payload.kind) => {
+                let task = TaskKey::new(self.payload.event_id.clone(), self.owner_id);
+                Some(TaskSummary::load(&task).await)
+            }
+            _ => None,
+        };
+
+        let body = serde_json::to_vec(&render(webhook.format, &self.payload, summary.as_ref()))?;
+        let secret = webhook.secret.open(get_keyring())?;
+
+        post(&webhook.url, &self.payload.kind, secret.as_bytes(), body).await
+    }
+}
+
+// Posts the body signed with the secret, returns the status the receiver answered with
+async fn post(url: &str, kind: &str, secret: &[u8], body: Vec<u8>) -> Result<u16, WebhookError> {
+    let signature = sign_payload(secret, &body)?;
+
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()?;
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, kind)
+        .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(response.status().as_u16())
+}
+
+#[async_trait]
+#[typetag::serde]
+impl AsyncRunnable for WebhookDeliveryTask {
+    async fn run(&self, _queue: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+        let webhook = match Webhook::from_uuid(self.webhook).await? {
+            Some(webhook) if webhook.enabled => webhook,
+            _ => return Ok(()),
+        };
+
+        let result = self.deliver(&webhook).await;
+
+        let (status, error) = match &result {
+            Ok(status) => (Some(*status as i32), None),
+            Err(WebhookError::Request(e)) => (
+                e.status().map(|status| status.as_u16() as i32),
+                Some(e.to_string()),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        WebhookDelivery::record(webhook.uuid, &self.payload, status, error).await;
+
+        result?;
+        Ok(())
+    }
+
+    fn uniq(&self) -> bool {
+        true
+    }
+
+    fn max_retries(&self) -> i32 {
+        5
+    }
+
+    fn task_type(&self) -> String {
+        "common".to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, GraphQLObject)]
+#[graphql(description = "A single attempt at delivering an event to a webhook")]
+pub struct WebhookDelivery {
+    #[graphql(ignore)]
+    pub id: i64,
+    pub webhook: Uuid,
+    pub task_event_id: i64,
+    pub event_id: String,
+    pub kind: String,
+    // Missing when the receiver couldn't be reached
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'a> TryFrom<&'a Row> for WebhookDelivery {
+    type Error = DBError;
+
+    fn try_from(row: &'a Row) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            webhook: row.try_get("webhook_uuid")?,
+            task_event_id: row.try_get("task_event_id")?,
+            event_id: row.try_get("event_id")?,
+            kind: row.try_get("kind")?,
+            status_code: row.try_get("status_code")?,
+            error: row.try_get("error")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+impl WebhookDelivery {
+    // Failing to log a delivery must not fail it, the receiver already got it
+    pub async fn record(
+        webhook: Uuid,
+        payload: &WebhookPayload,
+        status_code: Option<i32>,
+        error: Option<String>,
+    ) {
+        let db_manager = get_db_manager();
+        let result = db_manager
+            .execute(
+                "INSERT INTO webhook_deliveries (webhook_uuid, task_event_id, event_id, kind, \
+                 status_code, error) VALUES ($1, $2, $3, $4, $5, $6)",
+                &[
+                    &webhook,
+                    &payload.id,
+                    &payload.event_id,
+                    &payload.kind,
+                    &status_code,
+                    &error,
+                ],
+            )
+            .await;
+
+        if let Err(e) = result {
+            log::warn!("Failed to log delivery to {}: {}", webhook, e);
+        }
+    }
+
+    // Latest deliveries of a webhook, newest first
+    pub async fn for_webhook(webhook: Uuid, limit: i64) -> Result<Vec<WebhookDelivery>, DBError> {
+        let db_manager = get_db_manager();
+        let rows = db_manager
+            .query(
+                "SELECT * FROM webhook_deliveries WHERE webhook_uuid = $1 \
+                 ORDER BY id DESC LIMIT $2",
+                &[&webhook, &limit],
+            )
+            .await?;
+
+        let mut deliveries = Vec::new();
+        for row in rows {
+            deliveries.push(WebhookDelivery::try_from(&row)?);
+        }
+
+        Ok(deliveries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn webhook(events: Vec<TaskEventKind>) -> Webhook {
+        Webhook {
+            uuid: Uuid::nil(),
+            owner_uuid: None,
+            url: "http://localhost".to_string(),
+            secret: SealedToken::default(),
+            events,
+            format: WebhookFormat::Raw,
+            enabled: true,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign_payload(b"Jefe", b"what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn wants_the_task_lifecycle_without_a_filter() {
+        let webhook = webhook(Vec::new());
+
+        assert!(webhook.wants(TaskEventKind::Started));
+        assert!(webhook.wants(TaskEventKind::Finished));
+        assert!(!webhook.wants(TaskEventKind::VariantChosen));
+    }
+
+    #[test]
+    fn wants_only_what_the_filter_lists() {
+        let webhook = webhook(vec![TaskEventKind::VariantChosen]);
+
+        assert!(webhook.wants(TaskEventKind::VariantChosen));
+        assert!(!webhook.wants(TaskEventKind::Started));
+    }
+
+    #[test]
+    fn payload_from_event() {
+        let account = Uuid::from_u128(1);
+        let event = TaskEvent {
+            id: 42,
+            event_id: "event".to_string(),
+            owner_id: Some(Uuid::from_u128(2)),
+            kind: TaskEventKind::ReservationSucceeded,
+            message: "Reserved A-hytti for account".to_string(),
+            account: Some(account),
+            created_at: Utc::now(),
+        };
+
+        assert_eq!(
+            WebhookPayload::from(&event),
+            WebhookPayload {
+                id: 42,
+                event_id: "event".to_string(),
+                kind: TaskEventKind::ReservationSucceeded.as_str().to_string(),
+                message: "Reserved A-hytti for account".to_string(),
+                account: Some(account),
+                created_at: event.created_at,
+            }
+        );
+    }
+
+    // Accepts a single request and answers it with 204, returns the request as received
+    async fn receive_one(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn posts_signed_payloads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = tokio::spawn(receive_one(listener));
+
+        let body = br#"{"kind":"finished"}"#.to_vec();
+        let status = post(&url, "finished", b"secret", body.clone())
+            .await
+            .unwrap();
+        let request = receiver.await.unwrap();
+
+        assert_eq!(status, 204);
+
+        let (head, received) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(received.as_bytes(), body.as_slice());
+
+        let header = |name: &str| {
+            head.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+        };
+        assert_eq!(
+            header(SIGNATURE_HEADER),
+            Some(format!(
+                "sha256={}",
+                sign_payload(b"secret", received.as_bytes()).unwrap()
+            ))
+        );
+        assert_eq!(header(EVENT_HEADER), Some("finished".to_string()));
+    }
+}
@@ -1,21 +1,119 @@
+use chrono::Utc;
+use fang::asynk::async_queue::AsyncQueueable;
 use fang::asynk::async_worker_pool::AsyncWorkerPool;
-use fang::SleepParams;
+use fang::{AsyncRunnable, FangError, FangTaskState, Scheduled, SleepParams};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::{broadcast, Semaphore};
+use crate::notify::{subscribe, Notification};
 use crate::queue::Queue;
 
+// Must match `AsyncRunnable::task_type` of the tasks run by the pool
+const TASK_TYPE: &str = "common";
+// Tasks run by the pool at once, the waker runs no more than this on top of them
+const NUMBER_OF_WORKERS: u32 = 10;
+
 pub fn create_worker_pool(
     queue: Queue,
 ) -> AsyncWorkerPool<Queue> {
+    // Tasks are normally picked up through notifications, see `spawn_task_waker`. Polling is only
+    // a fallback for when the listener is down, tasks are scheduled well ahead of their sale so
+    // a slower poll is fine.
     let sleep_params = SleepParams::builder()
         .sleep_period(Duration::from_secs(1))
         .min_sleep_period(Duration::from_secs(1))
-        .max_sleep_period(Duration::from_secs(5))
+        .max_sleep_period(Duration::from_secs(15))
         .sleep_step(Duration::from_secs(1))
         .build();
 
     AsyncWorkerPool::builder()
-        .number_of_workers(10_u32)
+        .number_of_workers(NUMBER_OF_WORKERS)
         .queue(queue.clone())
         .sleep_params(sleep_params)
         .build()
 }
+
+// Runs tasks as soon as they are due instead of waiting for the pool to poll for them. Every
+// scheduled, updated or retried task is announced with its due time; the waker sleeps until then
+// and runs whatever is due. Tasks are fetched with `fetch_and_touch_task`, so a task is never run
+// by both the waker and the pool. However many tasks are announced, at most NUMBER_OF_WORKERS of
+// them run at once, the rest wait for a permit.
+pub fn spawn_task_waker(queue: Queue) {
+    let mut notifications = match subscribe() {
+        Some(notifications) => notifications,
+        None => {
+            log::warn!("Notification listener not running, tasks are only picked up by polling");
+            return;
+        }
+    };
+
+    let permits = Arc::new(Semaphore::new(NUMBER_OF_WORKERS as usize));
+
+    tokio::spawn(async move {
+        loop {
+            let task = match notifications.recv().await {
+                Ok(Notification::Task(task)) => task,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Missed {} task notifications", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            log::debug!("Task {} due at {}", task.id, task.scheduled_at);
+
+            let mut queue = queue.clone();
+            let permits = permits.clone();
+            tokio::spawn(async move {
+                let delay = (task.scheduled_at - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(delay).await;
+
+                // The semaphore is never closed
+                let _permit = permits.acquire().await.unwrap();
+                if let Err(e) = run_due_tasks(&mut queue).await {
+                    log::error!("Failed to run woken up task: {:?}", e);
+                }
+            });
+        }
+    });
+}
+
+// Runs due tasks until there are none left. Finished tasks are marked finished, failures are
+// retried with the task's backoff until it runs out of retries and are then marked failed.
+async fn run_due_tasks(queue: &mut Queue) -> Result<(), FangError> {
+    while let Some(task) = queue
+        .fetch_and_touch_task(Some(TASK_TYPE.to_string()))
+        .await?
+    {
+        let runnable: Box<dyn AsyncRunnable> = serde_json::from_value(task.metadata.clone())
+            .map_err(|e| FangError {
+                description: e.to_string(),
+            })?;
+
+        // Periodic tasks schedule their next run before running, so a failed run doesn't end them
+        if let Some(Scheduled::CronPattern(_)) = runnable.cron() {
+            queue.schedule_task(&*runnable).await?;
+        }
+
+        match runnable.run(queue).await {
+            Ok(_) => {
+                queue
+                    .update_task_state(task, FangTaskState::Finished)
+                    .await?;
+            }
+            Err(error) => {
+                if task.retries < runnable.max_retries() {
+                    let backoff_seconds = runnable.backoff(task.retries as u32);
+                    queue
+                        .schedule_retry(&task, backoff_seconds, &error.description)
+                        .await?;
+                } else {
+                    queue.fail_task(task, &error.description).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}